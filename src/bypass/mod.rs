@@ -0,0 +1,100 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+use log::{info, warn};
+
+use crate::config::BypassConfig;
+
+/// Whether the pipeline is bypassed, shared between every evdev worker and the watcher thread.
+///
+/// While bypassed nothing shouts or is recorded: every key, shoutable or not, is passed through
+/// from evdev as it was pressed.
+#[derive(Clone, Default)]
+pub struct Bypass(Arc<Inner>);
+
+#[derive(Default)]
+struct Inner {
+	active: AtomicBool,
+	chord: RwLock<HashSet<u16>>,
+	led: AtomicBool,
+}
+
+impl Bypass {
+	pub fn new(config: &BypassConfig) -> Self {
+		let bypass = Bypass::default();
+		bypass.configure(config);
+		bypass
+	}
+
+	pub fn configure(&self, config: &BypassConfig) {
+		let chord = match config.scancodes() {
+			Ok(chord) => chord,
+			Err(e) => {
+				// validated with the rest of the config, so this shouldn't happen
+				warn!("bypass chord disabled: {e:#}");
+				HashSet::new()
+			}
+		};
+		*self.0.chord.write().unwrap() = chord;
+		self.0.led.store(config.led, Ordering::Relaxed);
+	}
+
+	pub fn active(&self) -> bool {
+		self.0.active.load(Ordering::Relaxed)
+	}
+
+	/// Whether Scroll Lock should be lit on the keyboards, i.e. bypassed with `led` set.
+	pub fn led(&self) -> bool {
+		self.0.led.load(Ordering::Relaxed) && self.active()
+	}
+
+	fn toggle(&self) {
+		let active = !self.0.active.fetch_xor(true, Ordering::Relaxed);
+		if active {
+			info!("bypass chord pressed, shouting is off and all keys pass through");
+		} else {
+			info!("bypass chord pressed, shouting is back on");
+		}
+	}
+}
+
+/// Looks for the bypass chord in one keyboard's evdev events.
+#[derive(Default)]
+pub struct ChordDetector {
+	pressed: HashSet<u16>,
+	/// The key that completed the chord, whose press and release we keep to ourselves
+	swallowed: Option<u16>,
+}
+
+impl ChordDetector {
+	/// Toggle `bypass` if `e` completes the chord. Returns whether `e` should still be passed on.
+	pub fn observe(&mut self, bypass: &Bypass, e: &input_linux::sys::input_event) -> bool {
+		if e.type_ != input_linux::sys::EV_KEY as u16 {
+			return true;
+		}
+		match e.value {
+			0 => {
+				self.pressed.remove(&e.code);
+				if self.swallowed == Some(e.code) {
+					self.swallowed = None;
+					return false;
+				}
+				true
+			}
+			1 => {
+				self.pressed.insert(e.code);
+				let chord = bypass.0.chord.read().unwrap();
+				if !chord.is_empty() && chord.contains(&e.code) && chord.is_subset(&self.pressed) {
+					drop(chord);
+					bypass.toggle();
+					self.swallowed = Some(e.code);
+					return false;
+				}
+				true
+			}
+			// autorepeat of the swallowed key
+			_ => self.swallowed != Some(e.code),
+		}
+	}
+}
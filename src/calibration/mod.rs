@@ -0,0 +1,296 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::PathBuf;
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::{config, config::WatcherConfig, hid, keycode, watcher};
+
+const PROFILES_DIR_NAME: &str = "profiles";
+
+/// Fewest presses of a key in each of the soft and hard passes before we trust a cutoff for it
+const MIN_SAMPLES: usize = 3;
+
+/// Per-key overrides of the watcher thresholds. Anything left out falls back to the profile's
+/// `[defaults]`, then to the `[watcher]` section of the config.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyCalibration {
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub threshold_low: Option<f32>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub threshold: Option<f32>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub velocity_cutoff: Option<f32>,
+}
+
+impl KeyCalibration {
+	fn apply(&self, base: WatcherConfig) -> WatcherConfig {
+		WatcherConfig {
+			threshold_low: self.threshold_low.unwrap_or(base.threshold_low),
+			threshold: self.threshold.unwrap_or(base.threshold),
+			velocity_cutoff: self.velocity_cutoff.unwrap_or(base.velocity_cutoff),
+		}
+	}
+}
+
+/// A named calibration profile, stored as `~/.config/wooting-shouting/profiles/<name>.toml`.
+///
+/// Keys are named as in `input_linux::Key`, e.g. `[keys.A]` or `[keys.Num1]`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+	pub defaults: KeyCalibration,
+	pub keys: BTreeMap<String, KeyCalibration>,
+}
+
+/// Watcher thresholds resolved for every key, ready for `watcher::KeyWatcher`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Calibration {
+	pub profile: Option<String>,
+	pub default: WatcherConfig,
+	pub keys: HashMap<u16, WatcherConfig>,
+}
+
+impl Calibration {
+	/// Thresholds with no profile applied.
+	pub fn uniform(default: WatcherConfig) -> Self {
+		Calibration {
+			profile: None,
+			default,
+			keys: HashMap::new(),
+		}
+	}
+
+	/// The same thresholds with shouting switched off, so typing comes out as typed.
+	pub fn without_shouting(&self) -> Self {
+		let mut quiet = self.clone();
+		quiet.default.velocity_cutoff = f32::INFINITY;
+		for key in quiet.keys.values_mut() {
+			key.velocity_cutoff = f32::INFINITY;
+		}
+		quiet
+	}
+
+	pub fn for_key(&self, scancode: u16) -> WatcherConfig {
+		*self.keys.get(&scancode).unwrap_or(&self.default)
+	}
+}
+
+pub fn profiles_dir() -> Option<PathBuf> {
+	Some(config::config_dir()?.join(PROFILES_DIR_NAME))
+}
+
+pub fn profile_path(name: &str) -> anyhow::Result<PathBuf> {
+	if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+		bail!("invalid profile name {name:?}");
+	}
+	let dir = profiles_dir().context("couldn't work out a config directory for profiles")?;
+	Ok(dir.join(format!("{name}.toml")))
+}
+
+impl Profile {
+	pub fn save(&self, name: &str) -> anyhow::Result<PathBuf> {
+		let path = profile_path(name)?;
+		if let Some(dir) = path.parent() {
+			std::fs::create_dir_all(dir)
+				.with_context(|| format!("couldn't create profile directory {dir:?}"))?;
+		}
+		let text = toml::to_string_pretty(self).context("couldn't serialise calibration profile")?;
+		std::fs::write(&path, text)
+			.with_context(|| format!("couldn't write calibration profile {name:?} to {path:?}"))?;
+		Ok(path)
+	}
+
+	pub fn load(name: &str) -> anyhow::Result<Self> {
+		let path = profile_path(name)?;
+		let text = std::fs::read_to_string(&path)
+			.with_context(|| format!("couldn't read calibration profile {name:?} from {path:?}"))?;
+		toml::from_str(&text)
+			.with_context(|| format!("couldn't parse calibration profile {name:?} from {path:?}"))
+	}
+
+	/// Apply this profile on top of `base`, checking every key name and resolved threshold.
+	pub fn resolve(&self, name: &str, base: WatcherConfig) -> anyhow::Result<Calibration> {
+		let default = self.defaults.apply(base);
+		default
+			.validate()
+			.with_context(|| format!("invalid defaults in calibration profile {name:?}"))?;
+
+		let mut keys = HashMap::with_capacity(self.keys.len());
+		for (key_name, key) in self.keys.iter() {
+			let Some(scancode) = keycode::scancode_from_name(key_name) else {
+				bail!("unknown key {key_name:?} in calibration profile {name:?}");
+			};
+			let resolved = key.apply(default);
+			resolved.validate().with_context(|| {
+				format!("invalid calibration for key {key_name:?} in profile {name:?}")
+			})?;
+			keys.insert(scancode, resolved);
+		}
+
+		Ok(Calibration {
+			profile: Some(name.to_owned()),
+			default,
+			keys,
+		})
+	}
+}
+
+/// Resolve the calibration for `config`, loading its profile if one is selected.
+pub fn for_config(config: &config::Config) -> anyhow::Result<Calibration> {
+	match &config.profile {
+		Some(name) => Profile::load(name)?.resolve(name, config.watcher),
+		None => Ok(Calibration::uniform(config.watcher)),
+	}
+}
+
+/// Press velocities per key, found by running recorded `reports` through a `watcher::KeyWatcher`.
+pub fn press_velocities(
+	calibration: &Calibration,
+	reports: Vec<(std::time::Instant, Vec<hid::AnalogueReading>)>,
+) -> HashMap<u16, Vec<f32>> {
+	let mut velocities: HashMap<u16, Vec<f32>> = HashMap::new();
+	watcher::run_offline(calibration, reports, false, |k| {
+		velocities.entry(k.scancode).or_default().push(k.velocity);
+	});
+	velocities
+}
+
+/// How one key came out of `derive_profile`.
+pub struct KeyResult {
+	pub scancode: u16,
+	pub soft: Vec<f32>,
+	pub hard: Vec<f32>,
+	/// `None` if there weren't enough presses to pick one
+	pub cutoff: Option<f32>,
+	pub misclassified: usize,
+}
+
+/// Pick a velocity cutoff separating the `soft` and `hard` presses of every key that has enough
+/// of both, and write them into `profile`. All presses taken together give the profile's default.
+pub fn derive_profile(
+	profile: &mut Profile,
+	soft: &HashMap<u16, Vec<f32>>,
+	hard: &HashMap<u16, Vec<f32>>,
+) -> Vec<KeyResult> {
+	let all_soft: Vec<f32> = soft.values().flatten().copied().collect();
+	let all_hard: Vec<f32> = hard.values().flatten().copied().collect();
+	if all_soft.len() >= MIN_SAMPLES && all_hard.len() >= MIN_SAMPLES {
+		let (cutoff, _) = separating_cutoff(&all_soft, &all_hard);
+		profile.defaults.velocity_cutoff = Some(cutoff);
+	}
+
+	let scancodes: BTreeSet<u16> = soft.keys().chain(hard.keys()).copied().collect();
+	let mut results = vec![];
+	for scancode in scancodes {
+		let key_soft = soft.get(&scancode).cloned().unwrap_or_default();
+		let key_hard = hard.get(&scancode).cloned().unwrap_or_default();
+
+		let (cutoff, misclassified) =
+			if key_soft.len() >= MIN_SAMPLES && key_hard.len() >= MIN_SAMPLES {
+				let (cutoff, misclassified) = separating_cutoff(&key_soft, &key_hard);
+				(Some(cutoff), misclassified)
+			} else {
+				(None, 0)
+			};
+
+		if let (Some(cutoff), Some(name)) = (cutoff, keycode::key_name(scancode)) {
+			profile.keys.entry(name).or_default().velocity_cutoff = Some(cutoff);
+		}
+
+		results.push(KeyResult {
+			scancode,
+			soft: key_soft,
+			hard: key_hard,
+			cutoff,
+			misclassified,
+		});
+	}
+	results
+}
+
+/// The cutoff that puts the most of `soft` at or below it and the most of `hard` above it,
+/// along with how many presses it still gets wrong. Ties go to the middle of the tied range.
+fn separating_cutoff(soft: &[f32], hard: &[f32]) -> (f32, usize) {
+	let errors = |cutoff: f32| {
+		soft.iter().filter(|&&v| v > cutoff).count() + hard.iter().filter(|&&v| v <= cutoff).count()
+	};
+
+	let mut all: Vec<f32> = soft.iter().chain(hard).copied().collect();
+	all.sort_by(f32::total_cmp);
+	all.dedup();
+
+	let scored: Vec<(f32, usize)> = all
+		.windows(2)
+		.map(|w| (w[0] + w[1]) / 2.0)
+		.map(|cutoff| (cutoff, errors(cutoff)))
+		.collect();
+	let Some(best) = scored.iter().map(|(_, e)| *e).min() else {
+		return (all[0], errors(all[0]));
+	};
+	let tied: Vec<f32> = scored
+		.iter()
+		.filter(|(_, e)| *e == best)
+		.map(|(c, _)| *c)
+		.collect();
+	(tied[tied.len() / 2], best)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn cutoff_between_soft_and_hard() {
+		assert_eq!(separating_cutoff(&[100.0, 120.0, 140.0], &[200.0, 250.0, 300.0]), (170.0, 0));
+	}
+
+	#[test]
+	fn cutoff_with_overlap_picks_the_middle_of_the_ties() {
+		// 150 and 260 both get one press wrong
+		assert_eq!(separating_cutoff(&[100.0, 220.0], &[200.0, 300.0]), (260.0, 1));
+	}
+
+	#[test]
+	fn cutoff_for_a_single_velocity() {
+		assert_eq!(separating_cutoff(&[100.0], &[100.0]), (100.0, 1));
+	}
+
+	#[test]
+	fn derives_cutoffs_for_keys_with_enough_presses() {
+		let a = keycode::scancode_from_name("A").unwrap();
+		let b = keycode::scancode_from_name("B").unwrap();
+		let soft = HashMap::from([(a, vec![100.0, 110.0, 120.0])]);
+		let hard = HashMap::from([(a, vec![300.0, 310.0, 320.0]), (b, vec![300.0])]);
+
+		let mut profile = Profile::default();
+		let results = derive_profile(&mut profile, &soft, &hard);
+
+		assert_eq!(profile.defaults.velocity_cutoff, Some(210.0));
+		assert_eq!(profile.keys["A"].velocity_cutoff, Some(210.0));
+		assert!(!profile.keys.contains_key("B"));
+		let cutoffs: Vec<(u16, Option<f32>)> = results.iter().map(|r| (r.scancode, r.cutoff)).collect();
+		assert_eq!(cutoffs, [(a, Some(210.0)), (b, None)]);
+	}
+
+	#[test]
+	fn resolves_profile_over_watcher_config() {
+		let profile: Profile = toml::from_str(
+			"[defaults]\nvelocity_cutoff = 250.0\n[keys.A]\nthreshold = 0.8\n",
+		)
+		.unwrap();
+		let calibration = profile.resolve("test", WatcherConfig::default()).unwrap();
+
+		let a = calibration.for_key(keycode::scancode_from_name("A").unwrap());
+		assert_eq!((a.threshold, a.velocity_cutoff), (0.8, 250.0));
+		let b = calibration.for_key(keycode::scancode_from_name("B").unwrap());
+		assert_eq!((b.threshold, b.velocity_cutoff), (watcher::THRESHOLD, 250.0));
+	}
+
+	#[test]
+	fn rejects_unknown_keys_in_profile() {
+		let profile: Profile = toml::from_str("[keys.NotAKey]\nthreshold = 0.8\n").unwrap();
+		assert!(profile.resolve("test", WatcherConfig::default()).is_err());
+	}
+}
@@ -0,0 +1,1146 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::SyncSender;
+use std::thread::{self, JoinHandle};
+
+use anyhow::{bail, Context};
+use inotify::{Inotify, WatchMask};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use wooting_analog_plugin_dev::wooting_analog_common::DeviceID;
+
+use crate::layout::Layout;
+use crate::{calibration, hid, keycode, watcher};
+
+const CONFIG_DIR_NAME: &str = "wooting-shouting";
+const CONFIG_FILE_NAME: &str = "config.toml";
+
+const MAX_KEY_DELAY_MS: u64 = 100;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+	/// Name of the calibration profile to apply, see `calibration::Profile`
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub profile: Option<String>,
+	/// Keys that can shout: groups like `letters`, `digits`, `punctuation` and `alphanumerics`,
+	/// or single keys named as in `input_linux::Key`. All other keys are passed straight through
+	pub shoutable: Vec<String>,
+	pub watcher: WatcherConfig,
+	pub output: OutputConfig,
+	pub bypass: BypassConfig,
+	pub chords: ChordConfig,
+	pub gamepad: GamepadConfig,
+	pub mouse: MouseConfig,
+	pub stream: StreamConfig,
+	/// Settings for particular keyboards, keyed by `device_key`
+	#[serde(skip_serializing_if = "BTreeMap::is_empty")]
+	pub devices: BTreeMap<String, DeviceConfig>,
+	/// Resolved from `watcher` and `profile` by `load` and `watch`
+	#[serde(skip)]
+	pub calibration: calibration::Calibration,
+	/// Resolved like `calibration` for every keyboard in `devices`
+	#[serde(skip)]
+	pub device_calibrations: HashMap<DeviceID, calibration::Calibration>,
+	/// Resolved from `shoutable`, plus the `gamepad`, `mouse` and `output.midi` keys, as they need
+	/// reading analogue too
+	#[serde(skip)]
+	pub shoutable_keys: HashSet<u16>,
+	/// Resolved from `chords`
+	#[serde(skip)]
+	pub resolved_chords: watcher::Chords,
+	/// Resolved from `gamepad.keys`
+	#[serde(skip)]
+	pub gamepad_keys: HashMap<u16, GamepadInput>,
+	/// Resolved from `mouse.keys`
+	#[serde(skip)]
+	pub mouse_keys: HashMap<u16, MouseInput>,
+}
+
+impl Default for Config {
+	fn default() -> Self {
+		Config {
+			profile: None,
+			shoutable: vec![
+				"letters".to_string(),
+				"digits".to_string(),
+				"punctuation".to_string(),
+			],
+			watcher: WatcherConfig::default(),
+			output: OutputConfig::default(),
+			bypass: BypassConfig::default(),
+			chords: ChordConfig::default(),
+			gamepad: GamepadConfig::default(),
+			mouse: MouseConfig::default(),
+			stream: StreamConfig::default(),
+			devices: BTreeMap::new(),
+			calibration: calibration::Calibration::default(),
+			device_calibrations: HashMap::new(),
+			shoutable_keys: HashSet::new(),
+			resolved_chords: watcher::Chords::default(),
+			gamepad_keys: HashMap::new(),
+			mouse_keys: HashMap::new(),
+		}
+	}
+}
+
+/// The escape hatch for when shouting gets in the way, see `bypass::Bypass`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct BypassConfig {
+	/// Keys, named as in `input_linux::Key`, that toggle bypass when held together. Empty to
+	/// disable. They can't be in `shoutable`, as we only see those once the watcher fires them
+	pub chord: Vec<String>,
+	/// Light Scroll Lock while bypassed
+	pub led: bool,
+}
+
+impl Default for BypassConfig {
+	fn default() -> Self {
+		BypassConfig {
+			chord: vec!["LeftCtrl".to_string(), "RightCtrl".to_string()],
+			led: true,
+		}
+	}
+}
+
+impl BypassConfig {
+	pub fn scancodes(&self) -> anyhow::Result<HashSet<u16>> {
+		let chord = self.chord.iter().map(|name| (name, &()));
+		Ok(by_scancode("bypass.chord", chord)?.into_keys().collect())
+	}
+}
+
+/// Holding a key all the way down to send it with modifiers, e.g. bottoming out C for Ctrl+C.
+/// See `watcher::Chords`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChordConfig {
+	/// Keys, named as in `input_linux::Key`, that chord when held down deep. They must be
+	/// shoutable, as only those are read analogue. Empty to disable
+	pub keys: Vec<String>,
+	/// Keys held along with a chording key
+	pub modifiers: Vec<String>,
+	/// How far down, from 0.0 to 1.0, counts as deep
+	pub depth: f32,
+	/// How long a key has to stay deep to chord rather than type
+	pub hold_ms: u64,
+}
+
+impl Default for ChordConfig {
+	fn default() -> Self {
+		ChordConfig {
+			keys: vec![],
+			modifiers: vec!["LeftCtrl".to_string()],
+			depth: 0.95,
+			hold_ms: 250,
+		}
+	}
+}
+
+impl ChordConfig {
+	pub fn resolve(&self) -> anyhow::Result<watcher::Chords> {
+		let scancode = |name: &String, setting: &str| {
+			keycode::scancode_from_name(name)
+				.with_context(|| format!("unknown key {name:?} in chords.{setting}"))
+		};
+		Ok(watcher::Chords {
+			keys: self.keys.iter().map(|name| scancode(name, "keys")).collect::<Result<_, _>>()?,
+			modifiers: self
+				.modifiers
+				.iter()
+				.map(|name| scancode(name, "modifiers"))
+				.collect::<Result<_, _>>()?,
+			depth: self.depth,
+			hold: std::time::Duration::from_millis(self.hold_ms),
+		})
+	}
+
+	fn validate(&self, shoutable: &HashSet<u16>) -> anyhow::Result<()> {
+		let chords = self.resolve()?;
+		if !(self.depth > 0.0 && self.depth <= 1.0) {
+			bail!("chords.depth must be above 0.0 and at most 1.0, got {}", self.depth);
+		}
+		if self.hold_ms == 0 {
+			bail!("chords.hold_ms must be positive");
+		}
+		if !chords.keys.is_empty() && chords.modifiers.is_empty() {
+			bail!("chords.modifiers can't be empty");
+		}
+		for name in self.keys.iter() {
+			if keycode::scancode_from_name(name).is_some_and(|scancode| !shoutable.contains(&scancode)) {
+				bail!("{name:?} in chords.keys isn't a shoutable key");
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Mapping how far keys are pressed onto a virtual gamepad, see `gamepad::Gamepad`.
+///
+/// ```toml
+/// [gamepad.keys]
+/// W = "left_stick_up"
+/// A = "left_stick_left"
+/// S = "left_stick_down"
+/// D = "left_stick_right"
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct GamepadConfig {
+	/// Keys, named as in `input_linux::Key`, and what each moves. Empty for no gamepad
+	pub keys: BTreeMap<String, GamepadInput>,
+	/// How far down, from 0.0 to 1.0, a key has to go before it moves anything
+	pub deadzone: f32,
+	/// How far down a key has to go to move its stick or trigger all the way
+	pub outer_deadzone: f32,
+	/// Exponent of the response between the deadzones: 1.0 is linear, higher gives finer
+	/// control over small movements
+	pub curve: f32,
+	/// Whether the keys still type, or only move the gamepad
+	pub also_type: bool,
+}
+
+impl Default for GamepadConfig {
+	fn default() -> Self {
+		GamepadConfig {
+			keys: BTreeMap::new(),
+			deadzone: 0.1,
+			outer_deadzone: 0.95,
+			curve: 1.0,
+			also_type: false,
+		}
+	}
+}
+
+/// A direction of one of the gamepad's sticks, or one of its triggers.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum GamepadInput {
+	LeftStickUp,
+	LeftStickDown,
+	LeftStickLeft,
+	LeftStickRight,
+	RightStickUp,
+	RightStickDown,
+	RightStickLeft,
+	RightStickRight,
+	LeftTrigger,
+	RightTrigger,
+}
+
+impl GamepadConfig {
+	pub fn scancodes(&self) -> anyhow::Result<HashMap<u16, GamepadInput>> {
+		by_scancode("gamepad.keys", &self.keys)
+	}
+
+	/// How far, from 0.0 to 1.0, a key `depth` down moves its stick or trigger.
+	pub fn response(&self, depth: f32) -> f32 {
+		let range = self.outer_deadzone - self.deadzone;
+		((depth - self.deadzone) / range).clamp(0.0, 1.0).powf(self.curve)
+	}
+
+	fn validate(&self) -> anyhow::Result<()> {
+		self.scancodes()?;
+		let GamepadConfig {
+			deadzone,
+			outer_deadzone,
+			curve,
+			..
+		} = *self;
+		if !(0.0..1.0).contains(&deadzone) {
+			bail!("gamepad.deadzone must be at least 0.0 and below 1.0, got {deadzone}");
+		}
+		if !(outer_deadzone > deadzone && outer_deadzone <= 1.0) {
+			bail!(
+				"gamepad.outer_deadzone must be above gamepad.deadzone ({deadzone}) and at most \
+				 1.0, got {outer_deadzone}"
+			);
+		}
+		if !(curve.is_finite() && curve > 0.0) {
+			bail!("gamepad.curve must be a positive number, got {curve}");
+		}
+		Ok(())
+	}
+}
+
+/// Moving a virtual mouse pointer with how far keys are pressed, see `mouse::Mouse`.
+///
+/// ```toml
+/// [mouse.keys]
+/// I = "up"
+/// J = "left"
+/// K = "down"
+/// L = "right"
+/// U = "left_click"
+/// O = "right_click"
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MouseConfig {
+	/// Keys, named as in `input_linux::Key`, and what each does. Empty for no mouse
+	pub keys: BTreeMap<String, MouseInput>,
+	/// How fast the pointer moves with a key all the way down, in pixels per second
+	pub speed: f32,
+	/// How fast the wheel turns with a key all the way down, in notches per second
+	pub scroll_speed: f32,
+	/// How far down, from 0.0 to 1.0, a key has to go before it moves anything
+	pub deadzone: f32,
+	/// Exponent of the speed above the deadzone: 1.0 is linear, higher gives finer control
+	/// over slow movements
+	pub curve: f32,
+	/// How far down a click key has to go to press its button
+	pub click_depth: f32,
+	/// Whether the keys still type, or only drive the mouse
+	pub also_type: bool,
+}
+
+impl Default for MouseConfig {
+	fn default() -> Self {
+		MouseConfig {
+			keys: BTreeMap::new(),
+			speed: 1200.0,
+			scroll_speed: 10.0,
+			deadzone: 0.1,
+			curve: 2.0,
+			click_depth: 0.5,
+			also_type: false,
+		}
+	}
+}
+
+/// A direction to move the pointer or turn the wheel, or a button to click.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum MouseInput {
+	Up,
+	Down,
+	Left,
+	Right,
+	ScrollUp,
+	ScrollDown,
+	LeftClick,
+	RightClick,
+	MiddleClick,
+}
+
+impl MouseConfig {
+	pub fn scancodes(&self) -> anyhow::Result<HashMap<u16, MouseInput>> {
+		by_scancode("mouse.keys", &self.keys)
+	}
+
+	/// How fast, from 0.0 to 1.0 of full speed, a key `depth` down moves the pointer or wheel.
+	pub fn response(&self, depth: f32) -> f32 {
+		((depth - self.deadzone) / (1.0 - self.deadzone))
+			.clamp(0.0, 1.0)
+			.powf(self.curve)
+	}
+
+	fn validate(&self) -> anyhow::Result<()> {
+		self.scancodes()?;
+		let MouseConfig {
+			speed,
+			scroll_speed,
+			deadzone,
+			curve,
+			click_depth,
+			..
+		} = *self;
+		if !(speed.is_finite() && speed > 0.0) {
+			bail!("mouse.speed must be a positive number, got {speed}");
+		}
+		if !(scroll_speed.is_finite() && scroll_speed > 0.0) {
+			bail!("mouse.scroll_speed must be a positive number, got {scroll_speed}");
+		}
+		if !(0.0..1.0).contains(&deadzone) {
+			bail!("mouse.deadzone must be at least 0.0 and below 1.0, got {deadzone}");
+		}
+		if !(curve.is_finite() && curve > 0.0) {
+			bail!("mouse.curve must be a positive number, got {curve}");
+		}
+		if !(click_depth > 0.0 && click_depth <= 1.0) {
+			bail!("mouse.click_depth must be above 0.0 and at most 1.0, got {click_depth}");
+		}
+		Ok(())
+	}
+}
+
+/// Publishing readings and presses over UDP for other programs, see `stream::Stream`.
+///
+/// ```toml
+/// [stream]
+/// address = "127.0.0.1:9000"
+/// format = "json"
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct StreamConfig {
+	/// Where to send them, as `host:port` with an IP address. None for no stream
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub address: Option<String>,
+	pub format: StreamFormat,
+}
+
+/// How each message of the stream is encoded, one per UDP datagram.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamFormat {
+	/// OSC messages to `/wooting/reading` and `/wooting/key`
+	#[default]
+	Osc,
+	/// JSON objects with a `type` of `reading` or `key`
+	Json,
+}
+
+impl StreamConfig {
+	pub fn socket_addr(&self) -> anyhow::Result<Option<std::net::SocketAddr>> {
+		let Some(address) = &self.address else {
+			return Ok(None);
+		};
+		match address.parse() {
+			Ok(addr) => Ok(Some(addr)),
+			Err(_) => bail!("stream.address must be an IP address and port, got {address:?}"),
+		}
+	}
+}
+
+/// Settings for one keyboard, overriding the top level ones.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceConfig {
+	/// Whether hard presses on this keyboard shout at all
+	pub shout: bool,
+	/// Calibration profile for this keyboard instead of the top level `profile`
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub profile: Option<String>,
+}
+
+impl Default for DeviceConfig {
+	fn default() -> Self {
+		DeviceConfig {
+			shout: true,
+			profile: None,
+		}
+	}
+}
+
+/// How a keyboard's device id is written as a key of `[devices]`, e.g. `[devices.0x1f2e3d4c5b6a7988]`.
+pub fn device_key(id: DeviceID) -> String {
+	format!("{id:#x}")
+}
+
+fn parse_device_key(key: &str) -> anyhow::Result<DeviceID> {
+	let Some(hex) = key.strip_prefix("0x") else {
+		bail!("device ids start with 0x, got {key:?}");
+	};
+	DeviceID::from_str_radix(hex, 16).with_context(|| format!("bad device id {key:?}"))
+}
+
+/// `keys` by scancode rather than by name, as in `input_linux::Key`, with `section` in the error
+/// for a name that isn't a key.
+fn by_scancode<'a, V: Copy + 'a>(
+	section: &str,
+	keys: impl IntoIterator<Item = (&'a String, &'a V)>,
+) -> anyhow::Result<HashMap<u16, V>> {
+	keys.into_iter()
+		.map(|(name, value)| {
+			let scancode = keycode::scancode_from_name(name)
+				.with_context(|| format!("unknown key {name:?} in {section}"))?;
+			Ok((scancode, *value))
+		})
+		.collect()
+}
+
+/// Settings given on the command line, which win over the config file every time it is loaded.
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+	pub profile: Option<String>,
+}
+
+/// Thresholds used by `watcher::KeyWatcher` to decide when a press fires and whether it shouts.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct WatcherConfig {
+	/// Analogue value a key must stay above while it starts to come back up for the press to fire
+	pub threshold_low: f32,
+	/// Analogue value at which a press fires immediately
+	pub threshold: f32,
+	/// Velocity (analogue units per second) above which a press shouts
+	pub velocity_cutoff: f32,
+}
+
+impl Default for WatcherConfig {
+	fn default() -> Self {
+		WatcherConfig {
+			threshold_low: watcher::THRESHOLD_LOW,
+			threshold: watcher::THRESHOLD,
+			velocity_cutoff: watcher::VELOCITY_CUTOFF,
+		}
+	}
+}
+
+impl WatcherConfig {
+	pub fn validate(&self) -> anyhow::Result<()> {
+		let WatcherConfig {
+			threshold_low,
+			threshold,
+			velocity_cutoff,
+		} = *self;
+		for (name, v) in [
+			("threshold_low", threshold_low),
+			("threshold", threshold),
+			("velocity_cutoff", velocity_cutoff),
+		] {
+			if !v.is_finite() {
+				bail!("{name} must be a finite number, got {v}");
+			}
+		}
+		if !(threshold_low > 0.0 && threshold_low < 1.0) {
+			bail!("threshold_low must be between 0.0 and 1.0 (exclusive), got {threshold_low}");
+		}
+		if !(threshold > 0.0 && threshold <= 1.0) {
+			bail!("threshold must be above 0.0 and at most 1.0, got {threshold}");
+		}
+		if threshold_low >= threshold {
+			bail!("threshold_low ({threshold_low}) must be below threshold ({threshold})");
+		}
+		if velocity_cutoff <= 0.0 {
+			bail!("velocity_cutoff must be positive, got {velocity_cutoff}");
+		}
+		Ok(())
+	}
+}
+
+/// Settings for the uinput output device in `outputhid::OutputHid`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputConfig {
+	/// Gap between the injected Shift and the key it modifies
+	pub key_delay_ms: u64,
+	pub shout_mode: ShoutMode,
+	/// The keyboard layout the desktop uses, which decides which keys shout and what they type
+	pub layout: Layout,
+	pub words: WordConfig,
+	/// Capitalise the first letter after a `.`, `!` or `?`, see `context::ContextSink`
+	pub sentence_case: bool,
+	/// Velocity bands above the soft/hard split, in increasing order, see `Tier`
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub tiers: Vec<Tier>,
+	pub midi: MidiConfig,
+}
+
+/// Deciding whether to shout a word at a time, see `words::WordSink`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct WordConfig {
+	pub mode: WordMode,
+	/// Share of a word's letters that need hitting hard for the whole word to shout
+	pub threshold: f32,
+	/// A word also ends after this long without another letter
+	pub timeout_ms: u64,
+}
+
+impl Default for WordConfig {
+	fn default() -> Self {
+		WordConfig {
+			mode: WordMode::default(),
+			threshold: 0.5,
+			timeout_ms: 1000,
+		}
+	}
+}
+
+/// How a word that turns out to shout, or not, is made to come out that way.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum WordMode {
+	/// Every letter shouts on its own
+	#[default]
+	Off,
+	/// Type letters as they come, then backspace over the word and type it again if it needs
+	/// a different case. A word with a letter held long enough to autorepeat is left as it is
+	Retype,
+	/// Hold letters back until the word ends, then type it in the one case
+	Delay,
+}
+
+/// Playing notes on an ALSA sequencer port with the keys, see `midi::MidiSink`. Needs building
+/// with `--features midi`.
+///
+/// ```toml
+/// [output.midi.keys]
+/// Z = 60
+/// S = 61
+/// X = 62
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MidiConfig {
+	/// Keys, named as in `input_linux::Key`, and the MIDI note number each plays. Empty for no
+	/// MIDI port
+	pub keys: BTreeMap<String, u8>,
+	/// MIDI channel the notes go out on, from 1 to 16
+	pub channel: u8,
+	/// Press velocity, in the same units as `watcher.velocity_cutoff`, that plays at MIDI
+	/// velocity 127
+	pub max_velocity: f32,
+	/// Exponent of the velocity response: 1.0 is linear, higher leaves more room for quiet notes
+	pub curve: f32,
+	/// Send polyphonic aftertouch from how far down each key is held
+	pub aftertouch: bool,
+	/// Whether the keys still type, or only play notes
+	pub also_type: bool,
+	/// Resolved from `keys` by `Config::finish`
+	#[serde(skip)]
+	pub notes: HashMap<u16, u8>,
+}
+
+impl Default for MidiConfig {
+	fn default() -> Self {
+		MidiConfig {
+			keys: BTreeMap::new(),
+			channel: 1,
+			max_velocity: 500.0,
+			curve: 1.0,
+			aftertouch: true,
+			also_type: false,
+			notes: HashMap::new(),
+		}
+	}
+}
+
+impl MidiConfig {
+	pub fn scancodes(&self) -> anyhow::Result<HashMap<u16, u8>> {
+		by_scancode("output.midi.keys", &self.keys)
+	}
+
+	/// The MIDI velocity, from 1 to 127, for a press at `velocity`.
+	pub fn note_velocity(&self, velocity: f32) -> u8 {
+		let response = (velocity / self.max_velocity).clamp(0.0, 1.0).powf(self.curve);
+		1 + (response * 126.0).round() as u8
+	}
+
+	fn validate(&self) -> anyhow::Result<()> {
+		self.scancodes()?;
+		if cfg!(not(feature = "midi")) && !self.keys.is_empty() {
+			bail!("output.midi.keys needs building with --features midi");
+		}
+		if let Some((name, note)) = self.keys.iter().find(|(_, note)| **note > 127) {
+			bail!("output.midi.keys.{name} must be a note from 0 to 127, got {note}");
+		}
+		let MidiConfig {
+			channel,
+			max_velocity,
+			curve,
+			..
+		} = *self;
+		if !(1..=16).contains(&channel) {
+			bail!("output.midi.channel must be from 1 to 16, got {channel}");
+		}
+		if !(max_velocity.is_finite() && max_velocity > 0.0) {
+			bail!("output.midi.max_velocity must be a positive number, got {max_velocity}");
+		}
+		if !(curve.is_finite() && curve > 0.0) {
+			bail!("output.midi.curve must be a positive number, got {curve}");
+		}
+		Ok(())
+	}
+}
+
+/// What a hard press at least `velocity` fast does instead of the plain hard press.
+///
+/// ```toml
+/// [[output.tiers]]
+/// velocity = 400.0
+/// append = "!"
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tier {
+	/// Lowest press velocity in this tier, in the same units as `watcher.velocity_cutoff`
+	pub velocity: f32,
+	/// Whether the key shouts, as a hard press would
+	pub shout: bool,
+	/// Keys, named as in `input_linux::Key`, held down for as long as the key is
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub modifiers: Vec<String>,
+	/// Text typed after the word the key is in, once it ends
+	#[serde(skip_serializing_if = "String::is_empty")]
+	pub append: String,
+}
+
+impl Default for Tier {
+	fn default() -> Self {
+		Tier {
+			velocity: f32::INFINITY,
+			shout: true,
+			modifiers: vec![],
+			append: String::new(),
+		}
+	}
+}
+
+impl Tier {
+	pub fn modifier_scancodes(&self) -> anyhow::Result<Vec<u16>> {
+		self.modifiers
+			.iter()
+			.map(|name| {
+				keycode::scancode_from_name(name)
+					.with_context(|| format!("unknown key {name:?} in modifiers"))
+			})
+			.collect()
+	}
+
+	/// The keys that type `append` on `layout`, and whether each needs Shift.
+	pub fn append_keys(&self, layout: Layout) -> anyhow::Result<Vec<(u16, bool)>> {
+		self.append
+			.chars()
+			.map(|c| layout.key(c).with_context(|| format!("can't type {c:?} on {layout:?} layout")))
+			.collect()
+	}
+}
+
+/// What a hard press does to the case that Shift and Caps Lock would otherwise give.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ShoutMode {
+	/// Always come out shifted, i.e. upper case
+	#[default]
+	ForceShift,
+	/// Come out in the opposite case, so holding Shift and hitting hard gives lower case
+	InvertCase,
+}
+
+impl ShoutMode {
+	/// Whether a key comes out upper case, given whether it was hit hard enough to shout and
+	/// whether Shift and Caps Lock alone would make it upper case.
+	pub fn upper(self, shout: bool, natural: bool) -> bool {
+		match self {
+			ShoutMode::ForceShift => shout || natural,
+			ShoutMode::InvertCase => shout ^ natural,
+		}
+	}
+}
+
+impl Default for OutputConfig {
+	fn default() -> Self {
+		OutputConfig {
+			key_delay_ms: 5,
+			shout_mode: ShoutMode::default(),
+			layout: Layout::default(),
+			words: WordConfig::default(),
+			sentence_case: false,
+			tiers: vec![],
+			midi: MidiConfig::default(),
+		}
+	}
+}
+
+impl OutputConfig {
+	fn validate(&self) -> anyhow::Result<()> {
+		if self.key_delay_ms > MAX_KEY_DELAY_MS {
+			bail!(
+				"output.key_delay_ms must be at most {MAX_KEY_DELAY_MS}, got {}",
+				self.key_delay_ms
+			);
+		}
+		let WordConfig {
+			threshold,
+			timeout_ms,
+			..
+		} = self.words;
+		if !(threshold > 0.0 && threshold <= 1.0) {
+			bail!("output.words.threshold must be above 0.0 and at most 1.0, got {threshold}");
+		}
+		if timeout_ms == 0 {
+			bail!("output.words.timeout_ms must be positive");
+		}
+		let mut below = 0.0;
+		for (i, tier) in self.tiers.iter().enumerate() {
+			if !(tier.velocity.is_finite() && tier.velocity > below) {
+				bail!(
+					"output.tiers[{i}].velocity must be finite and above {below}, as tiers go in \
+					 increasing order, got {}",
+					tier.velocity
+				);
+			}
+			below = tier.velocity;
+			let context = || format!("invalid output.tiers[{i}]");
+			tier.modifier_scancodes().with_context(context)?;
+			tier.append_keys(self.layout).with_context(context)?;
+		}
+		self.midi.validate()?;
+		Ok(())
+	}
+
+	/// The last of `tiers` that `k` reaches, if any. Only presses the watcher found hard reach
+	/// one, so keys and keyboards calibrated not to shout never do, however fast.
+	pub fn tier(&self, k: &watcher::KeyEvent) -> Option<&Tier> {
+		if !k.caps {
+			return None;
+		}
+		self.tiers.iter().rev().find(|t| k.velocity >= t.velocity)
+	}
+
+	/// Whether `k` should shout: as the watcher decided, unless it reached a tier that says
+	/// otherwise, and only for keys that shout on `layout`.
+	pub fn shouts(&self, k: &watcher::KeyEvent) -> bool {
+		let shout = self.tier(k).map_or(k.caps, |tier| tier.shout);
+		shout && self.layout.shouts(k.scancode)
+	}
+
+	/// Whether `k` comes out upper case, given whether Shift and Caps Lock alone would make it so.
+	pub fn upper(&self, k: &watcher::KeyEvent, natural: bool) -> bool {
+		k.capitalise || self.shout_mode.upper(self.shouts(k), natural)
+	}
+}
+
+impl Config {
+	pub fn validate(&self) -> anyhow::Result<()> {
+		self.watcher.validate().context("invalid [watcher] section")?;
+		self.output.validate()?;
+		let shoutable = keycode::shoutable_scancodes(&self.shoutable).context("invalid shoutable")?;
+		self.bypass.scancodes()?;
+		self.chords.validate(&shoutable)?;
+		self.gamepad.validate()?;
+		self.mouse.validate()?;
+		self.stream.socket_addr()?;
+		for key in self.devices.keys() {
+			parse_device_key(key).context("invalid [devices] section")?;
+		}
+		Ok(())
+	}
+
+	/// Human readable `key: old -> new` lines for every setting that differs between `self` and
+	/// `new`, including the calibrations resolved from their profiles.
+	pub fn describe_changes(&self, new: &Config) -> Vec<String> {
+		let values = |config: &Config| -> Result<_, toml::ser::Error> {
+			Ok((toml::Value::try_from(config)?, config.resolved_value()?))
+		};
+		let (Ok((old_value, old_resolved)), Ok((new_value, new_resolved))) = (values(self), values(new))
+		else {
+			return vec!["config changed".to_string()];
+		};
+		let mut changes = vec![];
+		diff_values("", &old_value, &new_value, &mut changes);
+		// the profiles can change without the config file changing
+		diff_values("", &old_resolved, &new_resolved, &mut changes);
+		changes
+	}
+
+	/// The calibrations resolved by `finish` as a table for `diff_values`, with keys and
+	/// keyboards by name.
+	fn resolved_value(&self) -> Result<toml::Value, toml::ser::Error> {
+		let calibration_value = |calibration: &calibration::Calibration| {
+			let keys: BTreeMap<String, WatcherConfig> = calibration
+				.keys
+				.iter()
+				.map(|(scancode, key)| (keycode::key_name(*scancode).unwrap_or(scancode.to_string()), *key))
+				.collect();
+			let mut table = toml::Table::new();
+			table.insert("default".to_string(), toml::Value::try_from(calibration.default)?);
+			table.insert("keys".to_string(), toml::Value::try_from(keys)?);
+			Ok::<_, toml::ser::Error>(toml::Value::Table(table))
+		};
+		let mut devices = toml::Table::new();
+		for (id, calibration) in self.device_calibrations.iter() {
+			devices.insert(device_key(*id), calibration_value(calibration)?);
+		}
+		let mut table = toml::Table::new();
+		table.insert("calibration".to_string(), calibration_value(&self.calibration)?);
+		table.insert("device_calibrations".to_string(), toml::Value::Table(devices));
+		Ok(toml::Value::Table(table))
+	}
+
+	/// Fail if a key is claimed by more than one section, once `finish` has resolved them.
+	fn check_conflicts(&self) -> anyhow::Result<()> {
+		let name = |scancode: &u16| keycode::key_name(*scancode).unwrap_or(scancode.to_string());
+		let bypass = self.bypass.scancodes()?;
+		let sections: [(&str, HashSet<u16>); 5] = [
+			("bypass.chord", bypass.clone()),
+			("chords.keys", self.resolved_chords.keys.clone()),
+			("gamepad.keys", self.gamepad_keys.keys().copied().collect()),
+			("mouse.keys", self.mouse_keys.keys().copied().collect()),
+			(
+				"output.midi.keys",
+				self.output.midi.notes.keys().copied().collect(),
+			),
+		];
+		for (i, (section, keys)) in sections.iter().enumerate() {
+			for (other, other_keys) in sections[i + 1..].iter() {
+				if let Some(scancode) = keys.intersection(other_keys).min() {
+					bail!("{:?} is in both {section} and {other}", name(scancode));
+				}
+			}
+		}
+		if let Some(scancode) = bypass.intersection(&self.shoutable_keys).min() {
+			bail!("{:?} in bypass.chord is a shoutable key", name(scancode));
+		}
+		Ok(())
+	}
+
+	/// Apply `overrides` and resolve the calibration profile.
+	fn finish(mut self, overrides: &Overrides) -> anyhow::Result<Self> {
+		if overrides.profile.is_some() {
+			self.profile = overrides.profile.clone();
+		}
+		self.calibration = calibration::for_config(&self)?;
+		self.shoutable_keys = keycode::shoutable_scancodes(&self.shoutable)?;
+		self.resolved_chords = self.chords.resolve()?;
+		self.gamepad_keys = self.gamepad.scancodes()?;
+		self.shoutable_keys.extend(self.gamepad_keys.keys());
+		self.mouse_keys = self.mouse.scancodes()?;
+		self.shoutable_keys.extend(self.mouse_keys.keys());
+		self.output.midi.notes = self.output.midi.scancodes()?;
+		self.shoutable_keys.extend(self.output.midi.notes.keys());
+		self.check_conflicts()?;
+
+		self.device_calibrations.clear();
+		for (key, device) in self.devices.iter() {
+			let mut resolved = match &device.profile {
+				Some(name) => calibration::Profile::load(name)?
+					.resolve(name, self.watcher)
+					.with_context(|| format!("for device {key}"))?,
+				None => self.calibration.clone(),
+			};
+			if !device.shout {
+				resolved = resolved.without_shouting();
+			}
+			self.device_calibrations.insert(parse_device_key(key)?, resolved);
+		}
+		Ok(self)
+	}
+
+	/// The calibration for the keyboard with device id `id`.
+	pub fn calibration_for(&self, id: DeviceID) -> &calibration::Calibration {
+		self.device_calibrations.get(&id).unwrap_or(&self.calibration)
+	}
+
+	/// Read, parse and validate the config file at `path`.
+	pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+		let text = std::fs::read_to_string(path)
+			.with_context(|| format!("couldn't read config file {path:?}"))?;
+		let config: Config =
+			toml::from_str(&text).with_context(|| format!("couldn't parse config file {path:?}"))?;
+		config
+			.validate()
+			.with_context(|| format!("invalid config file {path:?}"))?;
+		Ok(config)
+	}
+}
+
+/// `$XDG_CONFIG_HOME/wooting-shouting`, falling back to `~/.config`.
+pub fn config_dir() -> Option<PathBuf> {
+	let config_home = match std::env::var_os("XDG_CONFIG_HOME") {
+		Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+		_ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+	};
+	Some(config_home.join(CONFIG_DIR_NAME))
+}
+
+pub fn default_path() -> Option<PathBuf> {
+	Some(config_dir()?.join(CONFIG_FILE_NAME))
+}
+
+fn diff_values(prefix: &str, old: &toml::Value, new: &toml::Value, changes: &mut Vec<String>) {
+	match (old, new) {
+		(toml::Value::Table(old), toml::Value::Table(new)) => {
+			let keys = old
+				.keys()
+				.chain(new.keys().filter(|k| !old.contains_key(*k)));
+			for key in keys {
+				let path = if prefix.is_empty() {
+					key.to_string()
+				} else {
+					format!("{prefix}.{key}")
+				};
+				match (old.get(key), new.get(key)) {
+					(Some(o), Some(n)) => diff_values(&path, o, n, changes),
+					(Some(o), None) => changes.push(format!("{path}: {o} -> (unset)")),
+					(None, Some(n)) => changes.push(format!("{path}: (unset) -> {n}")),
+					(None, None) => {}
+				}
+			}
+		}
+		(o, n) if o != n => changes.push(format!("{prefix}: {o} -> {n}")),
+		_ => {}
+	}
+}
+
+/// Load the config given by `--config`, or the default config file if there is one.
+///
+/// An explicitly given file must exist. A missing default file means the built-in defaults.
+/// Also returns the path that should be watched for changes, if any.
+pub fn load(
+	explicit: Option<&Path>,
+	overrides: &Overrides,
+) -> anyhow::Result<(Config, Option<PathBuf>)> {
+	let (config, path) = match explicit {
+		Some(path) => (Config::from_file(path)?, Some(path.to_owned())),
+		None => match default_path() {
+			Some(path) if path.exists() => (Config::from_file(&path)?, Some(path)),
+			Some(path) => {
+				info!("no config file at {path:?}, using defaults");
+				(Config::default(), Some(path))
+			}
+			None => {
+				info!("couldn't work out a config directory, using defaults");
+				(Config::default(), None)
+			}
+		},
+	};
+	Ok((config.finish(overrides)?, path))
+}
+
+/// Watch `path` and send every valid change to the running pipeline as `hid::Input::Reconfigure`.
+///
+/// The parent directory is watched rather than the file itself, so editors that save by
+/// renaming a new file over the old one are picked up, as is a config file created later.
+/// Invalid configs are logged and ignored, leaving `current` in force.
+pub fn watch(
+	path: PathBuf,
+	mut current: Config,
+	overrides: Overrides,
+	tx: SyncSender<hid::Input>,
+) -> anyhow::Result<JoinHandle<()>> {
+	let dir = match path.parent() {
+		Some(dir) if !dir.as_os_str().is_empty() => dir.to_owned(),
+		_ => PathBuf::from("."),
+	};
+	let file_name = path
+		.file_name()
+		.with_context(|| format!("config path {path:?} has no file name"))?
+		.to_owned();
+
+	let mut inotify = Inotify::init().context("couldn't initialise inotify")?;
+	inotify
+		.watches()
+		// not CREATE, which comes before anything is written and would load an empty file
+		.add(&dir, WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO)
+		.with_context(|| format!("couldn't watch {dir:?} for config changes"))?;
+
+	info!("watching {path:?} for config changes");
+
+	Ok(thread::spawn(move || {
+		let mut buffer = [0; 4096];
+		loop {
+			let events = match inotify.read_events_blocking(&mut buffer) {
+				Ok(events) => events,
+				Err(e) => {
+					error!("failed reading inotify events, no longer watching config: {e}");
+					return;
+				}
+			};
+			if !events
+				.into_iter()
+				.any(|ev| ev.name == Some(file_name.as_os_str()))
+			{
+				continue;
+			}
+
+			let new = match Config::from_file(&path).and_then(|c| c.finish(&overrides)) {
+				Ok(new) => new,
+				Err(e) => {
+					error!("rejecting new config, keeping the previous one: {e:#}");
+					continue;
+				}
+			};
+			let changes = current.describe_changes(&new);
+			if changes.is_empty() {
+				continue;
+			}
+			info!("reloading config: {}", changes.join(", "));
+
+			current = new.clone();
+			if tx.send(hid::Input::Reconfigure(Box::new(new))).is_err() {
+				info!("pipeline has closed, no longer watching config");
+				return;
+			}
+		}
+	}))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn resolved(toml: &str) -> anyhow::Result<Config> {
+		let config: Config = toml::from_str(toml)?;
+		config.validate()?;
+		config.finish(&Overrides::default())
+	}
+
+	#[test]
+	fn describes_changed_settings() {
+		let old = resolved("").unwrap();
+		let new = resolved("shoutable = [\"letters\"]\n[output]\nkey_delay_ms = 10\n").unwrap();
+		assert_eq!(
+			old.describe_changes(&new),
+			[
+				"output.key_delay_ms: 5 -> 10",
+				"shoutable: [\"letters\", \"digits\", \"punctuation\"] -> [\"letters\"]",
+			]
+		);
+		assert!(old.describe_changes(&old).is_empty());
+	}
+
+	#[test]
+	fn describes_changed_calibrations() {
+		let old = resolved("").unwrap();
+		let mut new = old.clone();
+		new.calibration.default.velocity_cutoff = 200.0;
+		let a = keycode::scancode_from_name("A").unwrap();
+		new.calibration.keys.insert(a, new.calibration.default);
+		new.device_calibrations.insert(0x1, old.calibration.clone());
+
+		let changes = old.describe_changes(&new);
+		assert_eq!(changes.len(), 3, "{changes:?}");
+		assert_eq!(changes[0], "calibration.default.velocity_cutoff: 180.0 -> 200.0");
+		assert!(changes[1].starts_with("calibration.keys.A: (unset) -> "), "{changes:?}");
+		assert!(changes[2].starts_with("device_calibrations.0x1: (unset) -> "), "{changes:?}");
+	}
+
+	fn conflict(toml: &str) -> String {
+		resolved(toml).unwrap_err().to_string()
+	}
+
+	#[test]
+	fn rejects_keys_in_two_sections() {
+		assert_eq!(
+			conflict("[gamepad.keys]\nW = \"left_stick_up\"\n[mouse.keys]\nW = \"up\"\n"),
+			"\"W\" is in both gamepad.keys and mouse.keys"
+		);
+		assert_eq!(
+			conflict("[chords]\nkeys = [\"C\"]\n[mouse.keys]\nC = \"left_click\"\n"),
+			"\"C\" is in both chords.keys and mouse.keys"
+		);
+		assert_eq!(
+			conflict("[bypass]\nchord = [\"F1\"]\n[gamepad.keys]\nF1 = \"left_stick_up\"\n"),
+			"\"F1\" is in both bypass.chord and gamepad.keys"
+		);
+		assert!(
+			resolved("[gamepad.keys]\nW = \"left_stick_up\"\n[mouse.keys]\nA = \"up\"\n").is_ok()
+		);
+	}
+
+	#[cfg(feature = "midi")]
+	#[test]
+	fn rejects_midi_keys_in_other_sections() {
+		assert_eq!(
+			conflict("[chords]\nkeys = [\"Z\"]\n[output.midi.keys]\nZ = 60\n"),
+			"\"Z\" is in both chords.keys and output.midi.keys"
+		);
+		assert_eq!(
+			conflict("[mouse.keys]\nZ = \"up\"\n[output.midi.keys]\nZ = 60\n"),
+			"\"Z\" is in both mouse.keys and output.midi.keys"
+		);
+	}
+
+	#[test]
+	fn rejects_shoutable_bypass_keys() {
+		assert_eq!(
+			conflict("[bypass]\nchord = [\"A\", \"B\"]\n"),
+			"\"A\" in bypass.chord is a shoutable key"
+		);
+	}
+}
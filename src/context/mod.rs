@@ -0,0 +1,275 @@
+use std::collections::{HashSet, VecDeque};
+use std::time::Instant;
+
+use input_linux::Key;
+use wooting_analog_plugin_dev::wooting_analog_common::DeviceID;
+
+use crate::config::OutputConfig;
+use crate::watcher::{ChordEvent, KeyEvent};
+use crate::{outputhid, sink::OutputSink};
+
+/// How many characters `TextContext` can take back with Backspace
+const HISTORY_LEN: usize = 256;
+
+/// Modifiers that turn typing into shortcuts
+const SHORTCUT_MODIFIERS: [Key; 6] = [
+	Key::LeftCtrl,
+	Key::RightCtrl,
+	Key::LeftAlt,
+	Key::RightAlt,
+	Key::LeftMeta,
+	Key::RightMeta,
+];
+/// Keys that don't type anything but don't move the cursor either
+const HARMLESS_KEYS: [Key; 5] = [
+	Key::LeftShift,
+	Key::RightShift,
+	Key::CapsLock,
+	Key::NumLock,
+	Key::ScrollLock,
+];
+
+/// Where the text being typed has got to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Position {
+	/// Somewhere in a sentence, or nowhere we know of
+	InSentence,
+	/// Just after a `.`, `!` or `?`, and any closing quotes or brackets
+	AfterTerminator,
+	/// After the whitespace that follows a terminator, and any opening quotes or brackets, where
+	/// the next letter starts a sentence
+	SentenceStart,
+}
+
+/// Follows the text as it is typed, from both watched keys and passthrough, to know when the next
+/// letter starts a sentence.
+///
+/// It can only see keys, not the text they land in, so it starts out knowing nothing and forgets
+/// everything on any key that doesn't type, like the arrows or Ctrl shortcuts. Backspace takes
+/// back the last character.
+pub struct TextContext {
+	position: Position,
+	/// Where we were before each character we can still take back
+	history: VecDeque<Position>,
+}
+
+impl Default for TextContext {
+	fn default() -> Self {
+		TextContext {
+			position: Position::InSentence,
+			history: VecDeque::new(),
+		}
+	}
+}
+
+impl TextContext {
+	pub fn sentence_start(&self) -> bool {
+		self.position == Position::SentenceStart
+	}
+
+	pub fn typed(&mut self, c: char) {
+		if self.history.len() == HISTORY_LEN {
+			self.history.pop_front();
+		}
+		self.history.push_back(self.position);
+		self.position = match (self.position, c) {
+			(_, '.' | '!' | '?') => Position::AfterTerminator,
+			(Position::AfterTerminator, '"' | '\'' | ')' | ']' | '}') => Position::AfterTerminator,
+			(Position::SentenceStart, '"' | '\'' | '(' | '[' | '{') => Position::SentenceStart,
+			(Position::AfterTerminator | Position::SentenceStart, c) if c.is_whitespace() => {
+				Position::SentenceStart
+			}
+			_ => Position::InSentence,
+		};
+	}
+
+	pub fn backspace(&mut self) {
+		match self.history.pop_back() {
+			Some(position) => self.position = position,
+			None => self.forget(),
+		}
+	}
+
+	/// Lose track, as the cursor may have moved anywhere.
+	pub fn forget(&mut self) {
+		self.position = Position::InSentence;
+		self.history.clear();
+	}
+}
+
+/// Capitalises the first letter of every sentence, in front of the sink that does the typing.
+/// Hard presses still shout anywhere else.
+///
+/// Only watched keys can be capitalised. A letter that isn't `shoutable` is passed straight
+/// through from evdev and comes out as typed, though it still counts towards the context.
+pub struct ContextSink {
+	inner: Box<dyn OutputSink>,
+	config: OutputConfig,
+	modifiers: outputhid::Modifiers,
+	/// `SHORTCUT_MODIFIERS` the user is holding
+	shortcut_held: HashSet<u16>,
+	context: TextContext,
+}
+
+impl ContextSink {
+	pub fn new(inner: Box<dyn OutputSink>, config: OutputConfig) -> Self {
+		ContextSink {
+			inner,
+			config,
+			modifiers: outputhid::Modifiers::default(),
+			shortcut_held: HashSet::new(),
+			context: TextContext::default(),
+		}
+	}
+}
+
+impl OutputSink for ContextSink {
+	fn send_key(&mut self, k: &KeyEvent) {
+		let layout = self.config.layout;
+		let natural = self.modifiers.upper(layout, k.scancode);
+		let mut k = k.clone();
+		if self.config.sentence_case
+			&& self.context.sentence_start()
+			&& layout.is_letter(k.scancode)
+		{
+			k.capitalise = true;
+		}
+
+		match layout.char(k.scancode, self.config.upper(&k, natural)) {
+			Some(c) if self.shortcut_held.is_empty() => self.context.typed(c),
+			_ => self.context.forget(),
+		}
+		self.inner.send_key(&k);
+	}
+
+	fn send_key_release(&mut self, device: DeviceID, code: u16, ts: Instant) {
+		self.inner.send_key_release(device, code, ts);
+	}
+
+	fn send_chord(&mut self, c: &ChordEvent) {
+		// a shortcut, which could do anything to the text
+		self.context.forget();
+		self.inner.send_chord(c);
+	}
+
+	fn send_passthrough(&mut self, evs: &[input_linux::sys::input_event], ts: Instant) {
+		let layout = self.config.layout;
+		for e in evs {
+			self.modifiers.observe(e);
+			if e.type_ != input_linux::sys::EV_KEY as u16 {
+				continue;
+			}
+			let Ok(key) = Key::from_code(e.code) else {
+				continue;
+			};
+			if SHORTCUT_MODIFIERS.contains(&key) {
+				if e.value == 0 {
+					self.shortcut_held.remove(&e.code);
+				} else {
+					self.shortcut_held.insert(e.code);
+				}
+				continue;
+			}
+			if e.value == 0 || HARMLESS_KEYS.contains(&key) {
+				continue;
+			}
+			match layout.char(e.code, self.modifiers.upper(layout, e.code)) {
+				_ if key == Key::Backspace => self.context.backspace(),
+				Some(c) if self.shortcut_held.is_empty() => self.context.typed(c),
+				_ => self.context.forget(),
+			}
+		}
+		self.inner.send_passthrough(evs, ts);
+	}
+
+	fn send_text(&mut self, text: &str, ts: Instant) {
+		text.chars().for_each(|c| self.context.typed(c));
+		self.inner.send_text(text, ts);
+	}
+
+	fn reconfigure(&mut self, config: OutputConfig) {
+		self.config = config.clone();
+		self.inner.reconfigure(config);
+	}
+
+	fn releaser(&self) -> Option<Box<dyn Fn() + Send>> {
+		self.inner.releaser()
+	}
+
+	fn next_due(&self) -> Option<Instant> {
+		self.inner.next_due()
+	}
+
+	fn flush(&mut self) {
+		self.inner.flush();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn after(text: &str) -> TextContext {
+		let mut context = TextContext::default();
+		text.chars().for_each(|c| context.typed(c));
+		context
+	}
+
+	#[test]
+	fn starts_knowing_nothing() {
+		assert!(!TextContext::default().sentence_start());
+	}
+
+	#[test]
+	fn sentences_start_after_terminators_and_whitespace() {
+		assert!(after("Hi. ").sentence_start());
+		assert!(after("Really?\n").sentence_start());
+		assert!(after("Go!  ").sentence_start());
+		assert!(!after("Hi.").sentence_start());
+		assert!(!after("e.g. x").sentence_start());
+		assert!(!after("Hi there ").sentence_start());
+	}
+
+	#[test]
+	fn quotes_and_brackets_around_sentences() {
+		assert!(after("\"Hi.\" ").sentence_start());
+		assert!(after("(Hi.) ").sentence_start());
+		assert!(after("Hi. \"(").sentence_start());
+		assert!(!after("Hi. x(").sentence_start());
+	}
+
+	#[test]
+	fn backspace_takes_back_a_character() {
+		let mut context = after("Hi. ");
+		context.backspace();
+		assert!(!context.sentence_start());
+		context.typed(' ');
+		assert!(context.sentence_start());
+
+		let mut context = after("Hi. x");
+		context.backspace();
+		assert!(context.sentence_start());
+	}
+
+	#[test]
+	fn backspace_past_what_we_saw_forgets() {
+		let mut context = after(" ");
+		context.backspace();
+		context.backspace();
+		context.typed(' ');
+		assert!(!context.sentence_start());
+
+		let text = format!("{}. ", "x".repeat(HISTORY_LEN));
+		let mut context = after(&text);
+		(0..HISTORY_LEN).for_each(|_| context.backspace());
+		assert_eq!(context.history.len(), 0);
+		assert_eq!(context.position, Position::InSentence);
+	}
+
+	#[test]
+	fn forgets_on_request() {
+		let mut context = after("Hi. ");
+		context.forget();
+		assert!(!context.sentence_start());
+	}
+}
@@ -0,0 +1,292 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::Context;
+use input_linux::{sys, uinput, AbsoluteAxis, AbsoluteInfo, AbsoluteInfoSetup};
+
+use crate::config::{Config, GamepadConfig, GamepadInput};
+use crate::hid;
+
+const STICK_MIN: i32 = -32768;
+const STICK_MAX: i32 = 32767;
+const TRIGGER_MAX: i32 = 255;
+
+/// The axis `input` moves, and which way.
+fn axis(input: GamepadInput) -> (AbsoluteAxis, i32) {
+	use GamepadInput::*;
+	match input {
+		LeftStickLeft => (AbsoluteAxis::X, -1),
+		LeftStickRight => (AbsoluteAxis::X, 1),
+		LeftStickUp => (AbsoluteAxis::Y, -1),
+		LeftStickDown => (AbsoluteAxis::Y, 1),
+		RightStickLeft => (AbsoluteAxis::RX, -1),
+		RightStickRight => (AbsoluteAxis::RX, 1),
+		RightStickUp => (AbsoluteAxis::RY, -1),
+		RightStickDown => (AbsoluteAxis::RY, 1),
+		LeftTrigger => (AbsoluteAxis::Z, 1),
+		RightTrigger => (AbsoluteAxis::RZ, 1),
+	}
+}
+
+/// The lowest and highest values of `axis`. Triggers only go one way.
+fn range(axis: AbsoluteAxis) -> (i32, i32) {
+	match axis {
+		AbsoluteAxis::Z | AbsoluteAxis::RZ => (0, TRIGGER_MAX),
+		_ => (STICK_MIN, STICK_MAX),
+	}
+}
+
+/// A virtual gamepad whose sticks and triggers follow how far the `[gamepad]` keys are pressed.
+pub struct Gamepad {
+	config: GamepadConfig,
+	keys: HashMap<u16, GamepadInput>,
+	virtual_device: bool,
+	handle: Option<uinput::UInputHandle<std::fs::File>>,
+	/// How far each mapped key moves its axis, from 0.0 to 1.0
+	responses: HashMap<u16, f32>,
+	/// What each axis was last set to
+	axes: BTreeMap<u16, i32>,
+}
+
+impl Gamepad {
+	pub fn new(config: &Config, virtual_device: bool) -> anyhow::Result<Self> {
+		let mut gamepad = Gamepad {
+			config: config.gamepad.clone(),
+			keys: config.gamepad_keys.clone(),
+			virtual_device,
+			handle: None,
+			responses: HashMap::new(),
+			axes: BTreeMap::new(),
+		};
+		gamepad.open()?;
+		Ok(gamepad)
+	}
+
+	/// Create the uinput device once a key is mapped to the gamepad.
+	fn open(&mut self) -> anyhow::Result<()> {
+		use std::os::unix::fs::OpenOptionsExt;
+
+		if !self.virtual_device || self.keys.is_empty() || self.handle.is_some() {
+			return Ok(());
+		}
+		let file = std::fs::OpenOptions::new()
+			.write(true)
+			.custom_flags(libc::O_NONBLOCK)
+			.open("/dev/uinput")
+			.context("couldn't open /dev/uinput for the gamepad")?;
+		let handle = uinput::UInputHandle::new(file);
+
+		handle.set_evbit(input_linux::EventKind::Absolute)?;
+		handle.set_evbit(input_linux::EventKind::Key)?;
+		handle.set_evbit(input_linux::EventKind::Synchronize)?;
+		// never pressed, but udev only calls it a joystick if it has gamepad buttons
+		for button in [
+			input_linux::Key::ButtonSouth,
+			input_linux::Key::ButtonEast,
+			input_linux::Key::ButtonNorth,
+			input_linux::Key::ButtonWest,
+		] {
+			handle.set_keybit(button)?;
+		}
+
+		use AbsoluteAxis::*;
+		let mut setup = vec![];
+		for axis in [X, Y, RX, RY, Z, RZ] {
+			handle.set_absbit(axis)?;
+			let (minimum, maximum) = range(axis);
+			setup.push(AbsoluteInfoSetup {
+				axis,
+				info: AbsoluteInfo {
+					value: 0,
+					minimum,
+					maximum,
+					fuzz: 0,
+					flat: 0,
+					resolution: 0,
+				},
+			});
+		}
+
+		let input_id = input_linux::InputId {
+			bustype: sys::BUS_USB,
+			vendor: 0x4711,
+			product: 0x0816,
+			version: 0,
+		};
+		handle
+			.create(&input_id, b"Wooting SHOUTING gamepad", 0, &setup)
+			.context("couldn't create the gamepad device")?;
+		log::info!("created virtual gamepad for {} keys", self.keys.len());
+		self.handle = Some(handle);
+		Ok(())
+	}
+
+	pub fn reconfigure(&mut self, config: &Config) {
+		self.centre();
+		self.config = config.gamepad.clone();
+		self.keys = config.gamepad_keys.clone();
+		if let Err(e) = self.open() {
+			log::error!("{e:#}");
+		}
+	}
+
+	/// Whether keys mapped to the gamepad should type as well.
+	pub fn also_type(&self) -> bool {
+		self.config.also_type
+	}
+
+	/// Take `reading` if it is for a mapped key, returning whether it was. Nothing moves until
+	/// `sync`.
+	pub fn take_input(&mut self, reading: &hid::AnalogueReading) -> bool {
+		if !self.keys.contains_key(&reading.scancode) {
+			return false;
+		}
+		self.responses
+			.insert(reading.scancode, self.config.response(reading.value));
+		true
+	}
+
+	/// Let go of every stick and trigger.
+	pub fn centre(&mut self) {
+		self.responses.clear();
+		self.sync();
+	}
+
+	/// Move every axis that has changed since the last sync.
+	pub fn sync(&mut self) {
+		let mut axes: BTreeMap<AbsoluteAxis, i32> = BTreeMap::new();
+		for (scancode, input) in self.keys.iter() {
+			let (axis, direction) = axis(*input);
+			let response = self.responses.get(scancode).copied().unwrap_or_default();
+			let (_, max) = range(axis);
+			*axes.entry(axis).or_default() += direction * (response * max as f32) as i32;
+		}
+
+		let time = libc::timeval {
+			tv_sec: 0,
+			tv_usec: 0,
+		};
+		let mut events = vec![];
+		for (&axis, &value) in axes.iter() {
+			let (min, max) = range(axis);
+			let value = value.clamp(min, max);
+			let code = axis as u16;
+			if self.axes.get(&code).copied().unwrap_or_default() == value {
+				continue;
+			}
+			self.axes.insert(code, value);
+			events.push(sys::input_event {
+				time,
+				type_: sys::EV_ABS as u16,
+				code,
+				value,
+			});
+		}
+		if events.is_empty() {
+			return;
+		}
+
+		let Some(handle) = &self.handle else {
+			log::debug!("gamepad axes {:?}", self.axes);
+			return;
+		};
+		events.push(sys::input_event {
+			time,
+			type_: sys::EV_SYN as u16,
+			code: sys::SYN_REPORT as u16,
+			value: 0,
+		});
+		if let Err(e) = handle.write(&events) {
+			log::error!("couldn't move the gamepad: {e}");
+		}
+	}
+}
+
+impl Drop for Gamepad {
+	fn drop(&mut self) {
+		self.centre();
+		if let Some(handle) = &self.handle {
+			if let Err(e) = handle.dev_destroy() {
+				log::error!("couldn't destroy the gamepad device: {e}");
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Instant;
+
+	use super::*;
+
+	/// A gamepad without a uinput device, for `keys` in `[gamepad.keys]` and a linear response.
+	fn gamepad(keys: &str) -> Gamepad {
+		let toml = format!(
+			"[gamepad]\ndeadzone = 0.0\nouter_deadzone = 1.0\ncurve = 1.0\n[gamepad.keys]\n{keys}"
+		);
+		let mut config: Config = toml::from_str(&toml).unwrap();
+		config.gamepad_keys = config.gamepad.scancodes().unwrap();
+		Gamepad::new(&config, false).unwrap()
+	}
+
+	fn press(gamepad: &mut Gamepad, key: &str, value: f32) -> bool {
+		let reading = hid::AnalogueReading {
+			scancode: crate::keycode::scancode_from_name(key).unwrap(),
+			value,
+			ts: Instant::now(),
+		};
+		gamepad.take_input(&reading)
+	}
+
+	fn axis_value(gamepad: &Gamepad, axis: AbsoluteAxis) -> i32 {
+		gamepad
+			.axes
+			.get(&(axis as u16))
+			.copied()
+			.unwrap_or_default()
+	}
+
+	#[test]
+	fn moves_sticks_and_triggers_with_depth() {
+		let mut gamepad = gamepad("W = \"left_stick_up\"\nE = \"right_trigger\"\n");
+		assert!(press(&mut gamepad, "W", 0.5));
+		assert!(press(&mut gamepad, "E", 1.0));
+		assert!(!press(&mut gamepad, "A", 1.0));
+		assert_eq!(
+			axis_value(&gamepad, AbsoluteAxis::Y),
+			0,
+			"nothing moves until sync"
+		);
+
+		gamepad.sync();
+		assert_eq!(axis_value(&gamepad, AbsoluteAxis::Y), -STICK_MAX / 2);
+		assert_eq!(axis_value(&gamepad, AbsoluteAxis::RZ), TRIGGER_MAX);
+
+		gamepad.centre();
+		assert_eq!(axis_value(&gamepad, AbsoluteAxis::Y), 0);
+		assert_eq!(axis_value(&gamepad, AbsoluteAxis::RZ), 0);
+	}
+
+	#[test]
+	fn opposing_keys_cancel_out() {
+		let mut gamepad = gamepad("A = \"left_stick_left\"\nD = \"left_stick_right\"\n");
+		press(&mut gamepad, "A", 1.0);
+		gamepad.sync();
+		assert_eq!(axis_value(&gamepad, AbsoluteAxis::X), -STICK_MAX);
+		press(&mut gamepad, "D", 1.0);
+		gamepad.sync();
+		assert_eq!(axis_value(&gamepad, AbsoluteAxis::X), 0);
+	}
+
+	#[test]
+	fn clamps_to_each_axis_range() {
+		let mut gamepad = gamepad(
+			"A = \"left_stick_left\"\nQ = \"left_stick_left\"\nE = \"left_trigger\"\nR = \"left_trigger\"\n",
+		);
+		for key in ["A", "Q", "E", "R"] {
+			press(&mut gamepad, key, 1.0);
+		}
+		gamepad.sync();
+		assert_eq!(axis_value(&gamepad, AbsoluteAxis::X), STICK_MIN);
+		assert_eq!(axis_value(&gamepad, AbsoluteAxis::Z), TRIGGER_MAX);
+	}
+}
@@ -12,11 +12,13 @@ use hidapi::{HidApi, HidDevice};
 use log::{error, info};
 
 use std::borrow::Borrow;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::os::raw::{c_float, c_ushort};
-use std::os::unix::prelude::OpenOptionsExt;
+use std::os::unix::prelude::AsRawFd;
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::SyncSender;
 use std::sync::{Arc, Mutex};
@@ -25,7 +27,8 @@ use std::{str, thread};
 use timer::{Guard, Timer};
 use wooting_analog_plugin_dev::wooting_analog_common::*;
 
-use crate::keycode::{self, SHOUTABLE_HIDCODES};
+use crate::bypass::{Bypass, ChordDetector};
+use crate::keycode;
 
 extern crate env_logger;
 
@@ -33,6 +36,8 @@ const ANALOG_BUFFER_SIZE: usize = 48;
 const ANALOG_MAX_SIZE: usize = 40;
 const WOOTING_VID: u16 = 0x31e3;
 const WOOTING_PID_MODE_MASK: u16 = 0xFFF0;
+/// How long an evdev worker waits for events before checking whether it should stop
+const EVDEV_POLL_TIMEOUT_MS: i32 = 100;
 
 /// Struct holding the information we need to find the device and the analog interface
 struct DeviceHardwareID {
@@ -78,6 +83,7 @@ trait DeviceImplementation: Send + Sync {
 		&self,
 		device: &HidDevice,
 		max_length: usize,
+		shoutable: &keycode::ShoutableKeys,
 	) -> Result<Option<Vec<AnalogueReading>>, ReadErrors> {
 		let mut buffer: [u8; ANALOG_BUFFER_SIZE] = [0; ANALOG_BUFFER_SIZE];
 		let res = device.read_timeout(&mut buffer, 100);
@@ -107,8 +113,10 @@ trait DeviceImplementation: Send + Sync {
 
 			if hidcode == 0 { continue; } //Get rid of entries where the code is 0
 
-			let scancode = keycode::hid_to_scancode(hidcode).unwrap();
-			if !keycode::SHOUTABLE_SCANCODES.contains(&scancode) {
+			let Some(scancode) = keycode::hid_to_scancode(hidcode) else {
+				continue;
+			};
+			if !shoutable.contains(scancode) {
 				continue;
 			}
 
@@ -272,12 +280,40 @@ pub struct PassThroughEvdev {
 	pub ts: std::time::Instant,
 }
 
+/// What comes into the pipeline. Readings and events say which keyboard they came from.
 pub enum Input {
-	Analogue(Vec<AnalogueReading>),
-	PassThrough(Vec<input_linux::sys::input_event>),
+	Analogue(DeviceID, Vec<AnalogueReading>),
+	/// Key events from evdev, to pass straight through, and when they were read
+	PassThrough(DeviceID, Vec<input_linux::sys::input_event>, std::time::Instant),
+	/// The keyboard has gone, so anything it was holding down has been let go
+	Disconnected(DeviceID),
+	Reconfigure(Box<crate::config::Config>),
 	Fin(),
 }
 
+/// Which keys go to the watcher and which are passed straight through, shared by every worker
+/// and updated when the config changes.
+#[derive(Clone, Default)]
+pub struct Routing {
+	pub bypass: Bypass,
+	pub shoutable: keycode::ShoutableKeys,
+}
+
+/// Something that sends analogue readings and passthrough events into the pipeline.
+pub trait InputSource: Send {
+	/// Start sending `Input`s to `tx` from other threads, split up according to `routing`.
+	fn start(&mut self, tx: SyncSender<Input>, routing: Routing) -> anyhow::Result<()>;
+
+	/// Stop sending and let go of any devices.
+	fn stop(&mut self);
+
+	/// Something that lets go of any grabbed devices from another thread without waiting on the
+	/// pipeline, for when it has stopped responding.
+	fn ungrabber(&self) -> Option<Box<dyn Fn() + Send>> {
+		None
+	}
+}
+
 impl DeviceImplementation for Wooting60HEARM {
 	fn device_hardware_id(&self) -> DeviceHardwareID {
 		DeviceHardwareID {
@@ -305,6 +341,7 @@ impl Device {
 		device: HidDevice,
 		device_impl: &'static Box<dyn DeviceImplementation>,
 		sender: SyncSender<Input>,
+		shoutable: keycode::ShoutableKeys,
 	) -> (DeviceID, Self) {
 		let id_hash = device_impl.get_device_id(device_info);
 
@@ -326,13 +363,14 @@ impl Device {
 					}
 
 					match device_impl
-						.get_analog_buffer(&device, ANALOG_MAX_SIZE)
+						.get_analog_buffer(&device, ANALOG_MAX_SIZE, &shoutable)
 						.into()
 					{
 						Ok(Some(data)) => {
-							if let Err(e) = sender.send(Input::Analogue(data)) {
-								error!("Sending failed, disconnected? {e:?}");
-								panic!("bang")
+							if sender.send(Input::Analogue(id_hash, data)).is_err() {
+								info!("pipeline has closed, stopping HID worker");
+								t_connected.store(false, Ordering::Relaxed);
+								break;
 							}
 						}
 						Ok(None) => {}
@@ -412,11 +450,15 @@ impl Drop for Device {
 	}
 }
 
+/// Every keyboard in use, by id, with the evdev nodes grabbed for it.
+type Devices = Arc<Mutex<HashMap<DeviceID, (Device, Vec<EvdevDevice>)>>>;
+
 pub struct WootingPlugin {
 	initialised: bool,
-	tx: SyncSender<Input>,
+	tx: Option<SyncSender<Input>>,
+	routing: Routing,
 	device_event_cb: Arc<Mutex<Option<Box<dyn Fn(DeviceEventType, &DeviceInfo) + Send>>>>,
-	devices: Arc<Mutex<HashMap<DeviceID, (Device, EvdevDevice)>>>,
+	devices: Devices,
 	timer: Timer,
 	worker_guard: Option<Guard>,
 }
@@ -437,10 +479,11 @@ static ref DEVICE_IMPLS: Vec<Box<dyn DeviceImplementation>> = vec![
 
 const PLUGIN_NAME: &str = "Wooting Official Plugin";
 impl WootingPlugin {
-	pub fn new(tx: SyncSender<Input>) -> Self {
+	pub fn new() -> Self {
 		WootingPlugin {
 			initialised: false,
-			tx: tx,
+			tx: None,
+			routing: Routing::default(),
 			device_event_cb: Arc::new(Mutex::new(None)),
 			devices: Arc::new(Mutex::new(Default::default())),
 			timer: timer::Timer::new(),
@@ -463,15 +506,29 @@ impl WootingPlugin {
 	}
 
 	fn init_worker(&mut self) -> SDKResult<u32> {
+		let Some(tx) = self.tx.clone() else {
+			return Err(WootingAnalogResult::UnInitialized).into();
+		};
 		let init_device_closure = |hid: &HidApi,
-		                           devices: &Arc<
-			Mutex<HashMap<DeviceID, (Device, EvdevDevice)>>,
-		>,
+		                           devices: &Devices,
 		                           device_event_cb: &Arc<
 			Mutex<Option<Box<dyn Fn(DeviceEventType, &DeviceInfo) + Send>>>,
 		>,
-		                           tx: SyncSender<Input>| {
+		                           tx: SyncSender<Input>,
+		                           routing: &Routing,
+		                           failed: &mut HashSet<DeviceID>| {
 			let device_infos: Vec<&DeviceInfoHID> = hid.device_list().collect();
+			// a keyboard we couldn't use is only logged about again once it has been unplugged
+			let present: HashSet<DeviceID> = device_infos
+				.iter()
+				.flat_map(|device_info| {
+					DEVICE_IMPLS
+						.iter()
+						.filter(|device_impl| device_impl.matches(device_info))
+						.map(|device_impl| device_impl.get_device_id(device_info))
+				})
+				.collect();
+			failed.retain(|id| present.contains(id));
 
 			for device_info in device_infos.iter() {
 				let m = device_info.manufacturer_string().unwrap_or_default();
@@ -488,28 +545,64 @@ impl WootingPlugin {
 							.contains_key(&device_impl.get_device_id(device_info))
 					{
 						// info!("Found device impl match: {:?}", device_info);
-						let evdev_path = find_evdev(device_info.product_id());
+						let id = device_impl.get_device_id(device_info);
+						let evdev_paths = match find_evdev(device_info) {
+							Ok(paths) => paths,
+							Err(e) => {
+								if failed.insert(id) {
+									error!("Not using {:?}: {e:#}", device_info.product_string());
+								}
+								continue;
+							}
+						};
 
 						let dev = match device_info.open_device(&hid) {
 							Ok(dev) => dev,
 
 							Err(e) => {
-								error!("Error opening HID Device: {}", e);
+								if failed.insert(id) {
+									error!("Error opening HID Device: {}", e);
+								}
 								continue;
 								//return WootingAnalogResult::Failure.into();
 							}
 						};
 
-						let (id, device) = Device::new(device_info, dev, device_impl, tx.clone());
-						let ev = EvdevDevice::new(&evdev_path, tx.clone());
+						// grab every node before starting on any, so a failure leaves none grabbed
+						let handles = match evdev_paths
+							.iter()
+							.map(|path| EvdevDevice::grab(path))
+							.collect::<anyhow::Result<Vec<_>>>()
+						{
+							Ok(handles) => handles,
+							Err(e) => {
+								if failed.insert(id) {
+									error!("Not using {:?}: {e:#}", device_info.product_string());
+								}
+								continue;
+							}
+						};
+						failed.remove(&id);
+						let (id, device) = Device::new(
+							device_info,
+							dev,
+							device_impl,
+							tx.clone(),
+							routing.shoutable.clone(),
+						);
+						let evs = handles
+							.into_iter()
+							.map(|h| EvdevDevice::new(h, id, tx.clone(), routing.clone()))
+							.collect();
 
 						{
-							devices.lock().unwrap().insert(id, (device, ev));
+							devices.lock().unwrap().insert(id, (device, evs));
 						}
 
 						info!(
-							"Found and opened the {:?} successfully!",
-							device_info.product_string()
+							"Found and opened the {:?} successfully! Its device id for [devices] in the config is {}",
+							device_info.product_string(),
+							crate::config::device_key(id)
 						);
 
 						device_event_cb.lock().unwrap().as_ref().and_then(|cb| {
@@ -546,12 +639,20 @@ impl WootingPlugin {
 		};
 
 		//We wanna call it in this thread first so we can get hold of any connected devices now so we can return an accurate result for initialise
-		init_device_closure(&hid, &self.devices, &self.device_event_cb, self.tx.clone());
+		let mut failed = HashSet::new();
+		init_device_closure(
+			&hid,
+			&self.devices,
+			&self.device_event_cb,
+			tx.clone(),
+			&self.routing,
+			&mut failed,
+		);
 
 		self.worker_guard = Some({
 			let t_devices = Arc::clone(&self.devices);
 			let t_device_event_cb = Arc::clone(&self.device_event_cb);
-			let tx = self.tx.clone();
+			let routing = self.routing.clone();
 			self.timer
 				.schedule_repeating(chrono::Duration::milliseconds(500), move || {
 					//Check if any of the devices have disconnected and get rid of them if they have
@@ -564,18 +665,29 @@ impl WootingPlugin {
 						}
 
 						for id in disconnected.iter() {
-							let (device, ev) = t_devices.lock().unwrap().remove(id).unwrap();
+							// dropping these stops their workers and releases the evdev grabs
+							let (device, evs) = t_devices.lock().unwrap().remove(id).unwrap();
+							drop(evs);
 							t_device_event_cb.lock().unwrap().as_ref().and_then(|cb| {
 								cb(DeviceEventType::Disconnected, &device.device_info);
 								Some(0)
 							});
+							// a closed pipeline is shutting down anyway
+							let _ = tx.send(Input::Disconnected(*id));
 						}
 					}
 
 					if let Err(e) = hid.refresh_devices() {
 						error!("We got error while refreshing devices. Err: {}", e);
 					}
-					init_device_closure(&hid, &t_devices, &t_device_event_cb, tx.clone());
+					init_device_closure(
+						&hid,
+						&t_devices,
+						&t_device_event_cb,
+						tx.clone(),
+						&routing,
+						&mut failed,
+					);
 				})
 		});
 		log::debug!("Started timer");
@@ -593,11 +705,13 @@ impl WootingPlugin {
 	}
 
 	pub fn unload(&mut self) {
-
+		// stop looking for new devices first, so the timer can't add one back
+		drop(self.worker_guard.take());
 		{
-			self.devices.try_lock().unwrap().drain();
+			// dropping the devices stops their workers and ungrabs them, even after a panic elsewhere
+			let mut devices = self.devices.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+			devices.clear();
 		}
-		drop(self.worker_guard.take());
 		self.initialised = false;
 
 		info!("{} unloaded", PLUGIN_NAME);
@@ -709,69 +823,116 @@ impl WootingPlugin {
 	}
 }
 
-fn find_evdev(product_id: u16) -> std::path::PathBuf {
-	use input_linux;
-	use std::os::unix::ffi::OsStringExt;
-	let des = std::fs::read_dir("/dev/input").unwrap();
-	let evdev_direntries = des.into_iter().filter_map(|f| {
-		let de = f.unwrap();
-		if de.file_name().to_string_lossy().starts_with("event") {
-			Some(de.path())
-		} else {
-			None
+/// The nearest USB device above `sysfs_path`, i.e. the first ancestor with an `idVendor`.
+fn usb_device_dir(sysfs_path: &Path) -> Option<PathBuf> {
+	let path = std::fs::canonicalize(sysfs_path).ok()?;
+	path.ancestors()
+		.find(|dir| dir.join("idVendor").exists())
+		.map(Path::to_path_buf)
+}
+
+/// The evdev nodes of the keyboard that `device_info` (a hidraw device) belongs to.
+///
+/// Matched through sysfs: the hidraw node and the evdev nodes must hang off the same USB device.
+/// A Wooting has several interfaces that can report keys, so every event node there with an `A`
+/// key is returned. Fails if there are none.
+fn find_evdev(device_info: &DeviceInfoHID) -> anyhow::Result<Vec<PathBuf>> {
+	let hidraw_path = PathBuf::from(device_info.path().to_string_lossy().into_owned());
+	let Some(hidraw_name) = hidraw_path.file_name() else {
+		anyhow::bail!("unexpected hidraw path {hidraw_path:?}");
+	};
+	let usb_dir = usb_device_dir(&Path::new("/sys/class/hidraw").join(hidraw_name).join("device"))
+		.with_context(|| format!("couldn't find the USB device for {hidraw_path:?} in sysfs"))?;
+
+	let mut found = vec![];
+	let entries = std::fs::read_dir("/sys/class/input").context("couldn't list /sys/class/input")?;
+	for entry in entries.flatten() {
+		let name = entry.file_name();
+		if !name.to_string_lossy().starts_with("event") {
+			continue;
 		}
-	});
-	let mut found_keyboards = evdev_direntries
-		.filter_map(|path| {
-			let fd = std::fs::OpenOptions::new().read(true).open(&path).unwrap();
-			let ev = input_linux::EvdevHandle::new(fd);
-			let id = ev.device_id().unwrap();
-			if id.vendor != WOOTING_VID && id.product != product_id {
-				return None;
-			}
-			let e = ev.key_bits().unwrap();
-			if !e
-				.into_iter()
-				.collect::<Vec<_>>()
-				.contains(&input_linux::Key::A)
-			{
-				return None;
+		if usb_device_dir(&entry.path().join("device")).as_ref() != Some(&usb_dir) {
+			continue;
+		}
+
+		let path = Path::new("/dev/input").join(&name);
+		let fd = match std::fs::OpenOptions::new().read(true).open(&path) {
+			Ok(fd) => fd,
+			Err(e) => {
+				log::warn!("couldn't open {path:?} to check it: {e}");
+				continue;
 			}
-			Some((path, ev))
-		})
-		.collect::<Vec<_>>();
+		};
+		let ev = input_linux::EvdevHandle::new(fd);
+		match ev.device_id() {
+			Ok(id) if id.vendor == device_info.vendor_id() => {}
+			_ => continue,
+		}
+		let has_keys = ev
+			.key_bits()
+			.map(|bits| bits.iter().any(|k| k == input_linux::Key::A))
+			.unwrap_or(false);
+		if !has_keys {
+			continue;
+		}
 
-	if found_keyboards.len() != 1 {
-		panic!("found 0 or multiple evdev candidates for keyboard!")
+		let ev_name = ev
+			.device_name()
+			.map(|n| String::from_utf8_lossy(&n).into_owned())
+			.unwrap_or_default();
+		log::info!("matched {hidraw_path:?} to {path:?} ({ev_name})");
+		found.push(path);
 	}
-	let (path, kb) = found_keyboards.remove(0);
 
-	let id = kb.device_id().unwrap();
-	let phys = &std::ffi::OsString::from_vec(kb.device_name().unwrap());
-	let name = &std::ffi::OsString::from_vec(kb.physical_location().unwrap());
-	log::info!("{phys:?} {name:? }{id:#?}");
-	drop(kb);
-
-	return path;
+	if found.is_empty() {
+		anyhow::bail!(
+			"no evdev keyboard found for {:?} at {hidraw_path:?}, is /dev/input readable?",
+			device_info.product_string().unwrap_or("keyboard")
+		);
+	}
+	found.sort();
+	Ok(found)
 }
 
+/// A grabbed evdev node of a keyboard, passing through the keys that aren't shoutable.
+///
+/// Dropping it stops the worker, which lets go of the grab.
 struct EvdevDevice {
-	//h: input_linux::EvdevHandle<std::fs::File>,
-	worker: JoinHandle<()>,
+	handle: Arc<input_linux::EvdevHandle<std::fs::File>>,
+	connected: Arc<AtomicBool>,
+	worker: Option<JoinHandle<()>>,
 }
 impl EvdevDevice {
-	fn new(path: &PathBuf, tx: SyncSender<Input>) -> Self {
+	/// Open and grab the evdev node at `path`. The grab lasts until the handle is dropped.
+	///
+	/// Opened for writing too if we can, to set the LEDs.
+	fn grab(path: &Path) -> anyhow::Result<input_linux::EvdevHandle<std::fs::File>> {
 		let fd = std::fs::OpenOptions::new()
 			.read(true)
+			.write(true)
 			.open(path)
-			.expect(&format!("couldn\'t open {path:?}"));
+			.or_else(|_| std::fs::OpenOptions::new().read(true).open(path))
+			.with_context(|| format!("couldn't open {path:?}"))?;
 
 		let h = input_linux::EvdevHandle::new(fd);
-		h.grab(true).unwrap();
+		h.grab(true)
+			.with_context(|| format!("couldn't grab {path:?}, is something else grabbing it?"))?;
+		Ok(h)
+	}
 
+	fn new(
+		h: input_linux::EvdevHandle<std::fs::File>,
+		id: DeviceID,
+		tx: SyncSender<Input>,
+		routing: Routing,
+	) -> Self {
+		let Routing { bypass, shoutable } = routing;
+
+		let handle = Arc::new(h);
 		let connected = Arc::new(AtomicBool::new(true));
 
 		let worker = {
+			let h = Arc::clone(&handle);
 			let t_connected = Arc::clone(&connected);
 			let mut buf: [input_linux::sys::input_event; 64] = [input_linux::sys::input_event {
 				time: libc::timeval {
@@ -784,22 +945,43 @@ impl EvdevDevice {
 			}; 64];
 
 			thread::spawn(move || {
+				let mut chord = ChordDetector::default();
+				// keys whose presses we passed through, so their releases follow them even if the
+				// key has since become shoutable or bypass has been turned off
+				let mut passed_held: HashSet<u16> = HashSet::new();
+				let mut led_lit = false;
 				loop {
 					if !t_connected.load(Ordering::Relaxed) {
 						break;
 					}
 
+					if bypass.led() != led_lit {
+						led_lit = !led_lit;
+						set_scroll_lock(&h, led_lit);
+					}
+
+					// wait with a timeout rather than block in read, so that we notice being dropped
+					match wait_readable(&*h, EVDEV_POLL_TIMEOUT_MS) {
+						Ok(true) => {}
+						Ok(false) => continue,
+						Err(e) => {
+							error!("Polling evdev failed, {:?}. Disconnecting device...", e);
+							break;
+						}
+					}
+
 					let events = match h.read(&mut buf) {
 						Ok(len) => &buf[0..len],
 						Err(e) => {
-							error!("Read failed from evdev, {:?}. Disconnecting device...", e);
-							t_connected.store(false, Ordering::Relaxed);
+							info!("Read failed from evdev, {:?}. Disconnecting device...", e);
 							break;
 						}
 					};
+					let ts = std::time::Instant::now();
 
 					let events = events
 						.iter()
+						.filter(|e| chord.observe(&bypass, e))
 						.filter_map(|e| {
 							if e.type_ == u16::try_from(input_linux::sys::EV_SYN).unwrap() {
 								return Some(*e);
@@ -807,23 +989,147 @@ impl EvdevDevice {
 							if e.type_ != u16::try_from(input_linux::sys::EV_KEY).unwrap() {
 								return None;
 							}
-							if keycode::SHOUTABLE_SCANCODES.contains(&e.code) {
+							if e.value == 0 && passed_held.remove(&e.code) {
+								return Some(*e);
+							}
+							if shoutable.contains(e.code) && !bypass.active() {
 								return None;
 							}
+							if e.value != 0 {
+								passed_held.insert(e.code);
+							}
 							return Some(*e);
 						})
 						.collect();
 
-					if let Err(e) = tx.try_send(Input::PassThrough(events)) {
-						error!("error sending, disconnected? {e:#?}");
-						panic!("bang");
+					if tx.send(Input::PassThrough(id, events, ts)).is_err() {
+						info!("pipeline has closed, stopping evdev worker");
+						break;
 					}
 				}
+				t_connected.store(false, Ordering::Relaxed);
+				if led_lit {
+					set_scroll_lock(&h, false);
+				}
+				// closing the handle would do this too, but make sure the keyboard is given back
+				let _ = h.grab(false);
 				drop(tx);
 			})
 		};
 
-		EvdevDevice { worker }
+		EvdevDevice {
+			handle,
+			connected,
+			worker: Some(worker),
+		}
+	}
+
+	/// Give the keyboard back without stopping the worker.
+	fn ungrab(&self) {
+		if let Err(e) = self.handle.grab(false) {
+			error!("couldn't ungrab evdev device: {e}");
+		}
+	}
+}
+
+impl Drop for EvdevDevice {
+	fn drop(&mut self) {
+		self.connected.store(false, Ordering::Relaxed);
+		if let Some(worker) = self.worker.take() {
+			if worker.join().is_err() {
+				error!("evdev worker panicked");
+			}
+		}
+	}
+}
+
+fn set_scroll_lock(h: &input_linux::EvdevHandle<std::fs::File>, lit: bool) {
+	let time = libc::timeval {
+		tv_sec: 0,
+		tv_usec: 0,
+	};
+	let events = [
+		input_linux::sys::input_event {
+			time,
+			type_: input_linux::sys::EV_LED as u16,
+			code: input_linux::sys::LED_SCROLLL as u16,
+			value: lit.into(),
+		},
+		input_linux::sys::input_event {
+			time,
+			type_: input_linux::sys::EV_SYN as u16,
+			code: input_linux::sys::SYN_REPORT as u16,
+			value: 0,
+		},
+	];
+	if let Err(e) = h.write(&events) {
+		log::warn!("couldn't set the Scroll Lock LED: {e}");
+	}
+}
+
+/// Wait up to `timeout_ms` for `fd` to have something to read, or to have gone away.
+fn wait_readable(fd: &impl AsRawFd, timeout_ms: i32) -> std::io::Result<bool> {
+	let mut pfd = libc::pollfd {
+		fd: fd.as_raw_fd(),
+		events: libc::POLLIN,
+		revents: 0,
+	};
+	// Safety: `pfd` is a single valid pollfd that outlives the call
+	let res = unsafe { libc::poll(&mut pfd, 1, timeout_ms) };
+	if res < 0 {
+		let e = std::io::Error::last_os_error();
+		if e.kind() == std::io::ErrorKind::Interrupted {
+			return Ok(false);
+		}
+		return Err(e);
+	}
+	Ok(res > 0)
+}
+
+impl InputSource for WootingPlugin {
+	fn start(&mut self, tx: SyncSender<Input>, routing: Routing) -> anyhow::Result<()> {
+		self.tx = Some(tx);
+		self.routing = routing;
+		let cb = |ev: DeviceEventType, info: &DeviceInfo| {
+			info!(
+				"{ev:?}: {} {} ({})",
+				info.manufacturer_name,
+				info.device_name,
+				crate::config::device_key(info.device_id)
+			);
+		};
+		if let Err(e) = self.initialise(Box::new(cb)).0 {
+			anyhow::bail!("failed to initialise the keyboard: {e:?}");
+		}
+		Ok(())
+	}
+
+	fn stop(&mut self) {
+		self.unload();
+	}
+
+	fn ungrabber(&self) -> Option<Box<dyn Fn() + Send>> {
+		let devices = Arc::clone(&self.devices);
+		Some(Box::new(move || {
+			// whoever holds the lock may be the one that's stuck
+			let Ok(devices) = devices.try_lock() else {
+				error!("couldn't get at the devices to ungrab them");
+				return;
+			};
+			for (_, evs) in devices.values() {
+				for ev in evs {
+					ev.ungrab();
+				}
+			}
+		}))
+	}
+}
+
+impl Drop for WootingPlugin {
+	fn drop(&mut self) {
+		if self.initialised {
+			self.unload();
+		}
 	}
 }
 
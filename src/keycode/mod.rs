@@ -1,7 +1,9 @@
 extern crate lazy_static;
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
 
+use anyhow::bail;
 use bimap::BiMap;
 use lazy_static::lazy_static;
 
@@ -40,7 +42,76 @@ pub fn hid_to_scancode(code: u16) -> Option<u16> {
 	SCANCODE_MAP.get_by_left(&(code as u8)).copied()
 }
 
+/// The `input_linux::Key` name of `scancode`, e.g. `A` or `Num1`, as used by the recorder.
+pub fn key_name(scancode: u16) -> Option<String> {
+	input_linux::Key::from_code(scancode)
+		.ok()
+		.map(|key| format!("{key:?}"))
+}
+
+/// Named groups of keys for the `shoutable` config setting, as ranges of HID codes.
+const SHOUTABLE_GROUPS: [(&str, &[std::ops::RangeInclusive<u8>]); 4] = [
+	("letters", &[0x04..=0x1d]),
+	("digits", &[0x1e..=0x27]),
+	// -=[]\ and ;'`,./
+	("punctuation", &[0x2d..=0x31, 0x33..=0x38]),
+	("alphanumerics", &[0x04..=0x27]),
+];
+
+/// The keys named by `names`, each either a group from `SHOUTABLE_GROUPS` or a key name.
+pub fn shoutable_scancodes(names: &[String]) -> anyhow::Result<HashSet<u16>> {
+	let mut scancodes = HashSet::new();
+	for name in names {
+		if let Some((_, ranges)) = SHOUTABLE_GROUPS.iter().find(|(group, _)| group == name) {
+			for hidcode in ranges.iter().cloned().flatten() {
+				scancodes.extend(hid_to_scancode(u16::from(hidcode)));
+			}
+		} else if let Some(scancode) = scancode_from_name(name) {
+			scancodes.insert(scancode);
+		} else {
+			let groups: Vec<&str> = SHOUTABLE_GROUPS.iter().map(|(group, _)| *group).collect();
+			bail!("{name:?} is neither a key name nor one of the groups {}", groups.join(", "));
+		}
+	}
+	Ok(scancodes)
+}
+
+/// The keys that go through the watcher rather than being passed straight through from evdev.
+///
+/// Shared by the HID reader, which keeps only these, and the evdev filter, which drops these, so
+/// that every key goes one way or the other.
+#[derive(Clone, Default)]
+pub struct ShoutableKeys(Arc<RwLock<HashSet<u16>>>);
+
+impl ShoutableKeys {
+	pub fn new(scancodes: HashSet<u16>) -> Self {
+		ShoutableKeys(Arc::new(RwLock::new(scancodes)))
+	}
+
+	pub fn contains(&self, scancode: u16) -> bool {
+		self.0.read().unwrap().contains(&scancode)
+	}
+
+	pub fn set(&self, scancodes: HashSet<u16>) {
+		*self.0.write().unwrap() = scancodes;
+	}
+}
+
+/// Look up a scancode by its `input_linux::Key` name, ignoring case.
+pub fn scancode_from_name(name: &str) -> Option<u16> {
+	KEY_NAMES.get(&name.to_ascii_lowercase()).copied()
+}
+
 lazy_static! {
+	//<lowercased key name, Scancode>
+	static ref KEY_NAMES: HashMap<String, u16> = {
+		let mut names = HashMap::new();
+		for key in input_linux::Key::iter() {
+			names.entry(format!("{key:?}").to_ascii_lowercase()).or_insert(u16::from(key));
+		}
+		names
+	};
+
 	//<HID code, Scancode>
 	static ref SCANCODE_MAP: BiMap<u8, u16> = {
 		let mut bimap: BiMap<u8, u16> = BiMap::new();
@@ -228,28 +299,32 @@ lazy_static! {
 		bimap.insert(0x6E, 0x0053); //NUMPAD_DECIMAL
 		bimap
 	};
+}
 
-	pub static ref SHOUTABLE_HIDCODES: HashSet::<u8> = {
-		let mut s: HashSet::<u8> = HashSet::<u8>::new();
-		for hidcode in 0x04..=0x27 { // A-Z, 1-9, 0
-			s.insert(hidcode);
-		}
-		for hidcode in 0x2d..=0x31 { // -=[]\
-			s.insert(hidcode);
-		}
-		for hidcode in 0x33..=0x38 { // ;'`,./
-			s.insert(hidcode);
-		}
-		s
-	};
 
-	pub static ref SHOUTABLE_SCANCODES: HashSet::<u16> = {
-		let mut s: HashSet::<u16> = HashSet::<u16>::new();
-		for hidcode in SHOUTABLE_HIDCODES.iter() {
-			let c = u16::from(*hidcode);
-			s.insert(hid_to_scancode(c).unwrap());
-		}
-		s
-	};
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn names(names: &[&str]) -> Vec<String> {
+		names.iter().map(|name| name.to_string()).collect()
+	}
 
+	#[test]
+	fn shoutable_groups_and_keys() {
+		let letters = shoutable_scancodes(&names(&["letters"])).unwrap();
+		assert_eq!(letters.len(), 26);
+		assert!(letters.contains(&scancode_from_name("Q").unwrap()));
+
+		let keys = shoutable_scancodes(&names(&["digits", "space"])).unwrap();
+		assert_eq!(keys.len(), 11);
+		assert!(keys.contains(&scancode_from_name("Num0").unwrap()));
+		assert!(keys.contains(&u16::from(input_linux::Key::Space)));
+	}
+
+	#[test]
+	fn shoutable_rejects_unknown_names() {
+		let e = shoutable_scancodes(&names(&["letters", "vowels"])).unwrap_err();
+		assert!(e.to_string().starts_with("\"vowels\" is neither"), "{e}");
+	}
 }
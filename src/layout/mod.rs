@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+
+use input_linux::Key;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+/// The keyboard layout the desktop is using, which decides what each key types.
+///
+/// Only the plain and Shift levels are known, AltGr and dead key combinations aren't.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Layout {
+	#[default]
+	Us,
+	Uk,
+	/// German QWERTZ
+	De,
+	/// French AZERTY
+	Fr,
+}
+
+impl Layout {
+	fn chars(self) -> &'static HashMap<u16, (char, char)> {
+		match self {
+			Layout::Us => &US,
+			Layout::Uk => &UK,
+			Layout::De => &DE,
+			Layout::Fr => &FR,
+		}
+	}
+
+	/// What `scancode` types, shifted or not.
+	pub fn char(self, scancode: u16, shifted: bool) -> Option<char> {
+		self.chars()
+			.get(&scancode)
+			.map(|&(plain, shift)| if shifted { shift } else { plain })
+	}
+
+	/// The key that types `c`, and whether it needs Shift.
+	pub fn key(self, c: char) -> Option<(u16, bool)> {
+		self.chars().iter().find_map(|(&scancode, &(plain, shift))| {
+			if c == plain {
+				Some((scancode, false))
+			} else if c == shift {
+				Some((scancode, true))
+			} else {
+				None
+			}
+		})
+	}
+
+	/// Whether `scancode` types a letter, with Shift giving its upper case. These are the keys
+	/// Caps Lock applies to.
+	pub fn is_letter(self, scancode: u16) -> bool {
+		match self.chars().get(&scancode) {
+			Some(&(plain, shift)) => {
+				plain.is_lowercase() && plain.to_uppercase().eq(std::iter::once(shift))
+			}
+			None => false,
+		}
+	}
+
+	/// Whether hitting `scancode` hard should shift it. Letters always shout. On the US layout,
+	/// which shouting was designed around, digits and punctuation shout their shifted symbols
+	/// too, but elsewhere Shift on those keys gives something unrelated (`7` to `/` on German,
+	/// `&` to `1` on French) so they are left as typed.
+	pub fn shouts(self, scancode: u16) -> bool {
+		self.is_letter(scancode) || (self == Layout::Us && self.chars().contains_key(&scancode))
+	}
+}
+
+/// The US layout with the keys in `changes` replaced.
+fn us_with(changes: &[(Key, char, char)]) -> HashMap<u16, (char, char)> {
+	let mut chars = US.clone();
+	for &(key, plain, shift) in changes {
+		chars.insert(u16::from(key), (plain, shift));
+	}
+	chars
+}
+
+lazy_static! {
+	//<Scancode, (plain, shifted)>
+	static ref US: HashMap<u16, (char, char)> = {
+		use Key::*;
+		let letters = [
+			A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+		];
+		let mut chars = HashMap::new();
+		for (key, c) in letters.iter().zip('a'..='z') {
+			chars.insert(u16::from(*key), (c, c.to_ascii_uppercase()));
+		}
+		for (key, plain, shift) in [
+			(Num1, '1', '!'), (Num2, '2', '@'), (Num3, '3', '#'), (Num4, '4', '$'), (Num5, '5', '%'),
+			(Num6, '6', '^'), (Num7, '7', '&'), (Num8, '8', '*'), (Num9, '9', '('), (Num0, '0', ')'),
+			(Minus, '-', '_'), (Equal, '=', '+'), (LeftBrace, '[', '{'), (RightBrace, ']', '}'),
+			(Backslash, '\\', '|'), (Semicolon, ';', ':'), (Apostrophe, '\'', '"'), (Grave, '`', '~'),
+			(Comma, ',', '<'), (Dot, '.', '>'), (Slash, '/', '?'),
+			(Space, ' ', ' '), (Enter, '\n', '\n'), (Tab, '\t', '\t'),
+		] {
+			chars.insert(u16::from(key), (plain, shift));
+		}
+		chars
+	};
+
+	static ref UK: HashMap<u16, (char, char)> = {
+		use Key::*;
+		us_with(&[
+			(Num2, '2', '"'), (Num3, '3', '£'), (Apostrophe, '\'', '@'), (Backslash, '#', '~'),
+			(Grave, '`', '¬'), (NonUsBackslashAndPipe, '\\', '|'),
+		])
+	};
+
+	static ref DE: HashMap<u16, (char, char)> = {
+		use Key::*;
+		us_with(&[
+			(Y, 'z', 'Z'), (Z, 'y', 'Y'),
+			(Num2, '2', '"'), (Num3, '3', '§'), (Num6, '6', '&'), (Num7, '7', '/'), (Num8, '8', '('),
+			(Num9, '9', ')'), (Num0, '0', '='), (Minus, 'ß', '?'), (Equal, '´', '`'),
+			(LeftBrace, 'ü', 'Ü'), (RightBrace, '+', '*'), (Semicolon, 'ö', 'Ö'),
+			(Apostrophe, 'ä', 'Ä'), (Grave, '^', '°'), (Backslash, '#', '\''),
+			(Comma, ',', ';'), (Dot, '.', ':'), (Slash, '-', '_'), (NonUsBackslashAndPipe, '<', '>'),
+		])
+	};
+
+	static ref FR: HashMap<u16, (char, char)> = {
+		use Key::*;
+		us_with(&[
+			(Q, 'a', 'A'), (A, 'q', 'Q'), (W, 'z', 'Z'), (Z, 'w', 'W'), (Semicolon, 'm', 'M'),
+			(Num1, '&', '1'), (Num2, 'é', '2'), (Num3, '"', '3'), (Num4, '\'', '4'), (Num5, '(', '5'),
+			(Num6, '-', '6'), (Num7, 'è', '7'), (Num8, '_', '8'), (Num9, 'ç', '9'), (Num0, 'à', '0'),
+			(Minus, ')', '°'), (Equal, '=', '+'), (LeftBrace, '^', '¨'), (RightBrace, '$', '£'),
+			(Apostrophe, 'ù', '%'), (Grave, '²', '²'), (Backslash, '*', 'µ'),
+			(M, ',', '?'), (Comma, ';', '.'), (Dot, ':', '/'), (Slash, '!', '§'),
+			(NonUsBackslashAndPipe, '<', '>'),
+		])
+	};
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const LAYOUTS: [Layout; 4] = [Layout::Us, Layout::Uk, Layout::De, Layout::Fr];
+
+	fn code(key: Key) -> u16 {
+		u16::from(key)
+	}
+
+	#[test]
+	fn keys_type_what_they_are_found_for() {
+		for layout in LAYOUTS {
+			for &(plain, shift) in layout.chars().values() {
+				for c in [plain, shift] {
+					let (scancode, shifted) = layout.key(c).unwrap();
+					assert_eq!(layout.char(scancode, shifted), Some(c), "{c:?} on {layout:?}");
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn finds_keys_moved_by_the_layout() {
+		assert_eq!(Layout::Us.key('A'), Some((code(Key::A), true)));
+		assert_eq!(Layout::De.key('z'), Some((code(Key::Y), false)));
+		assert_eq!(Layout::Uk.key('@'), Some((code(Key::Apostrophe), true)));
+		assert_eq!(Layout::Fr.key('1'), Some((code(Key::Num1), true)));
+		assert_eq!(Layout::Us.key('é'), None);
+	}
+
+	#[test]
+	fn letters_are_what_caps_lock_shifts() {
+		assert!(Layout::Us.is_letter(code(Key::M)));
+		assert!(!Layout::Fr.is_letter(code(Key::M)));
+		assert!(Layout::Fr.is_letter(code(Key::Semicolon)));
+		assert!(Layout::De.is_letter(code(Key::LeftBrace)));
+		// é shifts to 2, not É
+		assert!(!Layout::Fr.is_letter(code(Key::Num2)));
+		assert!(!Layout::Us.is_letter(code(Key::Num2)));
+	}
+
+	#[test]
+	fn only_us_symbols_shout() {
+		assert!(Layout::Us.shouts(code(Key::Num1)));
+		assert!(Layout::Us.shouts(code(Key::Slash)));
+		assert!(!Layout::De.shouts(code(Key::Num7)));
+		assert!(!Layout::Fr.shouts(code(Key::Num1)));
+		assert!(Layout::De.shouts(code(Key::Apostrophe)));
+		assert!(!Layout::Us.shouts(code(Key::LeftCtrl)));
+	}
+}
@@ -1,138 +1,808 @@
 //extern crate wooting_analog_wrapper;
-use wooting_analog_plugin_dev::wooting_analog_common as wooting;
 
 //use wooting_analog_wrapper as sdk;
 
 //pub use sdk::{DeviceInfo, FromPrimitive, HIDCodes, ToPrimitive, WootingAnalogResult};
 
 use std::{
-	collections::{HashSet},
-	thread,
+	collections::HashMap,
+	io::Write,
+	path::PathBuf,
+	sync::{
+		atomic::{AtomicUsize, Ordering},
+		mpsc::{RecvTimeoutError, SyncSender, TrySendError},
+		Arc, Mutex,
+	},
+	thread::{self, JoinHandle},
 };
+use clap::{Parser, Subcommand};
 //use sdk::SDKResult;
 use env_logger;
 use log::*;
+use wooting_analog_plugin_dev::wooting_analog_common::DeviceID;
 
+mod bypass;
+mod calibration;
+mod config;
+mod context;
+mod gamepad;
 mod hid;
 mod keycode;
+mod layout;
+#[cfg(feature = "midi")]
+mod midi;
+mod mouse;
 mod outputhid;
 mod watcher;
 mod recorder;
+mod replay;
+mod simulated;
+mod sink;
+mod stream;
+mod watchdog;
+mod words;
 
 const READ_CHANNEL_BUF_SIZE: usize = 128;
 const OUT_CHANNEL_BUF_SIZE: usize = 8;
 const RECORD_CHANNEL_BUF_SIZE: usize = 64;
 
+const CALIBRATION_PASSAGE: &str = "the five boxing wizards jump quickly. pack my box with five dozen liquor jugs. \
+sphinx of black quartz, judge my vow. how vexingly quick daft zebras jump. 1234567890";
+const DEFAULT_PROFILE: &str = "default";
+
+#[derive(Parser)]
+#[command(about = "Shout when you hit your Wooting keyboard hard")]
+struct Args {
+	/// Config file to use instead of ~/.config/wooting-shouting/config.toml
+	#[arg(long, global = true)]
+	config: Option<PathBuf>,
+	/// Calibration profile to use, overriding `profile` in the config file
+	#[arg(long, global = true)]
+	profile: Option<String>,
+	#[command(flatten)]
+	io: Io,
+	#[command(subcommand)]
+	command: Option<Command>,
+}
+
+/// Where the pipeline reads keys from and sends them to.
+#[derive(clap::Args)]
+struct Io {
+	/// Write what would be typed to this file, or - for stdout, instead of a uinput keyboard
+	#[arg(long, global = true, value_name = "PATH")]
+	output_to: Option<PathBuf>,
+	/// Read from a simulated keyboard instead of a real one: text:<text>, session:<epoch|latest>
+	/// or script:<path>. Simulated typing isn't recorded
+	#[arg(long, global = true, value_name = "SOURCE")]
+	simulate: Option<simulated::Source>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+	/// Record some soft and some hard typing and save per-key velocity cutoffs as a calibration profile
+	Calibrate,
+	/// Run a recorded session back through the watcher without the keyboard and print the result
+	Replay(replay::ReplayArgs),
+}
+
 
 fn main() {
 	env_logger::init();
+	watchdog::install_panic_hook();
+
+	let args = Args::parse();
+	let overrides = config::Overrides {
+		profile: args.profile,
+	};
+	let (config, config_path) = match config::load(args.config.as_deref(), &overrides) {
+		Ok(loaded) => loaded,
+		Err(e) => {
+			error!("{e:#}");
+			std::process::exit(1);
+		}
+	};
+	info!("using {config:?}");
 
-	let (hid_tx, in_rx) = std::sync::mpsc::sync_channel::<hid::Input>(READ_CHANNEL_BUF_SIZE);
-	let (ev_tx, ev_rx) =
-		std::sync::mpsc::sync_channel::<OutputHidEvent>(OUT_CHANNEL_BUF_SIZE);
-	let (record_tx, record_rx) =
-		std::sync::mpsc::sync_channel::<hid::AnalogueReading>(RECORD_CHANNEL_BUF_SIZE);
+	let res = match args.command {
+		None => run(config, config_path, overrides, &args.io),
+		Some(Command::Calibrate) => calibrate(config, &args.io),
+		Some(Command::Replay(replay_args)) => replay::run(&config, &replay_args),
+	};
+	if let Err(e) = res {
+		error!("{e:#}");
+		std::process::exit(1);
+	}
+}
 
+fn run(
+	config: config::Config,
+	config_path: Option<PathBuf>,
+	overrides: config::Overrides,
+	io: &Io,
+) -> anyhow::Result<()> {
+	let sink = io.sink(config.output.clone())?;
+	let pipeline = Pipeline::start(
+		&config,
+		io.source()?,
+		sink,
+		io.gamepad(&config)?,
+		io.mouse(&config)?,
+		io.record(),
+	)?;
 
+	if let Some(path) = config_path {
+		if let Err(e) = config::watch(path, config.clone(), overrides, pipeline.hid_tx.clone()) {
+			warn!("config hot reloading disabled: {e:#}");
+		}
+	}
 
-	let mut watcher = watcher::KeyWatcher::new(ev_tx.clone());
+	{
+		// also called on SIGTERM
+		let hid_tx = pipeline.hid_tx.clone();
+		ctrlc::set_handler(move || {
+			info!("shutting down");
+			let _ = hid_tx.send(hid::Input::Fin());
+		})?;
+	}
 
-	let mut last_pressed = HashSet::<u16>::new();
+	pipeline.join();
+	Ok(())
+}
 
-	let mut reader = hid::WootingPlugin::new(hid_tx.clone());
+impl Io {
+	/// The keyboard, or a `simulated::SimulatedDevice` if `--simulate` was given.
+	fn source(&self) -> anyhow::Result<Box<dyn hid::InputSource>> {
+		Ok(match &self.simulate {
+			Some(source) => Box::new(simulated::SimulatedDevice::from_source(source)?),
+			None => Box::new(hid::WootingPlugin::new()),
+		})
+	}
 
-	{
-		let cb = |ev: wooting::DeviceEventType, info: &wooting::DeviceInfo| {};
-		let res = reader.initialise(Box::new(cb));
-		let Ok(_) = res.0 else {
-			panic!("failed to init")
+	/// The uinput keyboard, or a `sink::TextSink` if `--output-to` was given, with sentence case,
+	/// shouting a word at a time and MIDI notes on top if configured. The MIDI notes are only
+	/// logged if `--output-to` was given.
+	fn sink(&self, config: config::OutputConfig) -> anyhow::Result<Box<dyn sink::OutputSink>> {
+		let sink: Box<dyn sink::OutputSink> = match &self.output_to {
+			Some(path) => Box::new(sink::TextSink::open(path, config.clone())?),
+			None => Box::new(outputhid::OutputHid::new(config.clone())?),
 		};
+		wrap_sink(sink, config, self.output_to.is_none())
+	}
+
+	/// The gamepad, only logging its axes if `--output-to` was given.
+	fn gamepad(&self, config: &config::Config) -> anyhow::Result<gamepad::Gamepad> {
+		gamepad::Gamepad::new(config, self.output_to.is_none())
+	}
+
+	/// The mouse, only logging its movements if `--output-to` was given.
+	fn mouse(&self, config: &config::Config) -> anyhow::Result<mouse::Mouse> {
+		mouse::Mouse::new(config, self.output_to.is_none())
+	}
+
+	fn record(&self) -> bool {
+		self.simulate.is_none()
+	}
+}
+
+/// Put the sinks that decide what gets typed in front of `sink`, which does the typing. MIDI
+/// notes only go out to a sequencer port if `midi_port`.
+fn wrap_sink(
+	sink: Box<dyn sink::OutputSink>,
+	config: config::OutputConfig,
+	midi_port: bool,
+) -> anyhow::Result<Box<dyn sink::OutputSink>> {
+	// words first, so that sentence case can capitalise a word that didn't shout
+	let sink = Box::new(context::ContextSink::new(sink, config.clone()));
+	let sink: Box<dyn sink::OutputSink> = Box::new(words::WordSink::new(sink, config.clone()));
+	#[cfg(feature = "midi")]
+	let sink = Box::new(midi::MidiSink::new(sink, config, midi_port)?);
+	#[cfg(not(feature = "midi"))]
+	let _ = midi_port;
+	Ok(sink)
+}
+
+/// Have the user type `CALIBRATION_PASSAGE` softly and then hard, and work out per-key velocity
+/// cutoffs from what the recorder saw.
+fn calibrate(config: config::Config, io: &Io) -> anyhow::Result<()> {
+	let profile_name = config.profile.clone().unwrap_or(DEFAULT_PROFILE.to_string());
 
+	// nothing should shout while we're calibrating
+	let mut quiet = config.clone();
+	quiet.calibration = config.calibration.without_shouting();
+	for calibration in quiet.device_calibrations.values_mut() {
+		*calibration = calibration.without_shouting();
+	}
+	let sink = io.sink(quiet.output.clone())?;
+	let pipeline = Pipeline::start(
+		&quiet,
+		io.source()?,
+		sink,
+		io.gamepad(&quiet)?,
+		io.mouse(&quiet)?,
+		true,
+	)?;
+	let dropped = Arc::clone(&pipeline.dropped);
+	let pipeline = Arc::new(Mutex::new(Some(pipeline)));
 
+	{
+		let pipeline = Arc::clone(&pipeline);
 		ctrlc::set_handler(move || {
-			info!("got handler!");
-			info!("unloaded!");
-			hid_tx.send(hid::Input::Fin()).unwrap();
-		}).unwrap();
+			eprintln!("calibration aborted");
+			if let Some(pipeline) = pipeline.lock().unwrap().take() {
+				pipeline.stop();
+			}
+			std::process::exit(130);
+		})?;
+	}
+
+	println!("Calibrating profile {profile_name:?}.");
+	let soft = prompt_pass("SOFTLY, as gently as you normally would")?;
+	let hard = prompt_pass("HARD, as if you were shouting")?;
+
+	if let Some(pipeline) = pipeline.lock().unwrap().take() {
+		pipeline.stop();
+	}
+	let dropped = dropped.load(Ordering::Relaxed);
+	if dropped > 0 {
+		anyhow::bail!("the recorder couldn't keep up and lost {dropped} readings, try calibrating again");
+	}
+
+	let con = recorder::sqlite_connection()?;
+	let velocities = |(from, to): (f64, f64)| -> anyhow::Result<_> {
+		let events = recorder::events_between(&con, from, to)?;
+		let reports = recorder::to_reports(&events, std::time::Instant::now());
+		Ok(calibration::press_velocities(&config.calibration, reports))
+	};
+	let soft = velocities(soft)?;
+	let hard = velocities(hard)?;
+
+	// start afresh only if there is no profile yet, rather than overwrite one we couldn't read
+	let mut profile = match calibration::Profile::load(&profile_name) {
+		Ok(profile) => profile,
+		Err(e) if is_not_found(&e) => calibration::Profile::default(),
+		Err(e) => return Err(e),
+	};
+	let results = calibration::derive_profile(&mut profile, &soft, &hard);
+
+	println!();
+	println!("{:<12} {:>5} {:>10} {:>5} {:>10} {:>10} {:>6}", "key", "soft", "median", "hard", "median", "cutoff", "wrong");
+	for r in results.iter() {
+		let name = keycode::key_name(r.scancode).unwrap_or(format!("{}", r.scancode));
+		let cutoff = match r.cutoff {
+			Some(c) => format!("{c:.1}"),
+			None => "too few".to_string(),
+		};
+		println!(
+			"{name:<12} {:>5} {:>10.1} {:>5} {:>10.1} {cutoff:>10} {:>6}",
+			r.soft.len(),
+			median(&r.soft),
+			r.hard.len(),
+			median(&r.hard),
+			r.misclassified,
+		);
+	}
+	if let Some(c) = profile.defaults.velocity_cutoff {
+		println!("default cutoff: {c:.1}");
+	}
 
+	let path = profile.save(&profile_name)?;
+	println!("saved calibration profile {profile_name:?} to {path:?}");
+	Ok(())
+}
+
+/// Show the passage, wait for the user to type it and press Enter, and return the unix time range
+/// they were typing in.
+fn prompt_pass(how: &str) -> anyhow::Result<(f64, f64)> {
+	println!();
+	println!("Type the following {how}, then press Enter:");
+	println!();
+	println!("    {CALIBRATION_PASSAGE}");
+	println!();
+	std::io::stdout().flush()?;
+
+	let from = unix_now();
+	let mut line = String::new();
+	std::io::stdin().read_line(&mut line)?;
+	let to = unix_now();
+	Ok((from, to))
+}
+
+fn is_not_found(e: &anyhow::Error) -> bool {
+	e.root_cause()
+		.downcast_ref::<std::io::Error>()
+		.is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound)
+}
+
+fn unix_now() -> f64 {
+	std::time::SystemTime::now()
+		.duration_since(std::time::UNIX_EPOCH)
+		.unwrap()
+		.as_secs_f64()
+}
+
+fn median(values: &[f32]) -> f32 {
+	if values.is_empty() {
+		return f32::NAN;
 	}
+	let mut sorted = values.to_vec();
+	sorted.sort_by(f32::total_cmp);
+	sorted[sorted.len() / 2]
+}
+
+/// Watcher state for one keyboard, so two keyboards pressing the same key don't interfere.
+struct DeviceWatcher {
+	watcher: watcher::KeyWatcher,
+	releases: watcher::ReleaseTracker,
+}
+
+/// The reader, watcher, output and recorder threads, wired together.
+struct Pipeline {
+	reader: Box<dyn hid::InputSource>,
+	hid_tx: SyncSender<hid::Input>,
+	threads: Vec<JoinHandle<()>>,
+	/// How many readings were dropped because the recorder couldn't keep up
+	dropped: Arc<AtomicUsize>,
+}
+
+impl Pipeline {
+	fn start(
+		config: &config::Config,
+		mut reader: Box<dyn hid::InputSource>,
+		mut sink: Box<dyn sink::OutputSink>,
+		mut gamepad: gamepad::Gamepad,
+		mut mouse: mouse::Mouse,
+		record: bool,
+	) -> anyhow::Result<Self> {
+		let (hid_tx, in_rx) = std::sync::mpsc::sync_channel::<hid::Input>(READ_CHANNEL_BUF_SIZE);
+		let (ev_tx, ev_rx) =
+			std::sync::mpsc::sync_channel::<OutputHidEvent>(OUT_CHANNEL_BUF_SIZE);
+		let (record_tx, record_rx) =
+			std::sync::mpsc::sync_channel::<hid::AnalogueReading>(RECORD_CHANNEL_BUF_SIZE);
+
 
 
-	let t_in = {
-		thread::spawn(move || {
-			for input in in_rx {
-				match input {
-					hid::Input::Analogue(kk) => {
-						let mut pressed = HashSet::<u16>::new();
-						for input in &kk {
-							pressed.insert(input.scancode);
-							watcher.take_input(input);
-							record_tx.send(input.to_owned()).unwrap();
-							//info!("got {code}:{analog}")
+		let mut config = config.clone();
+		let mut devices: HashMap<DeviceID, DeviceWatcher> = HashMap::new();
+
+		let routing = hid::Routing {
+			bypass: bypass::Bypass::new(&config.bypass),
+			shoutable: keycode::ShoutableKeys::new(config.shoutable_keys.clone()),
+		};
+		reader.start(hid_tx.clone(), routing.clone())?;
+
+		let (stream, t_stream) = stream::Stream::spawn(&config.stream);
+
+		let dropped = Arc::new(AtomicUsize::new(0));
+
+		// give the keyboard back and let go of our keys if a thread gets stuck or panics
+		let rescue = || -> Box<dyn Fn() + Send> {
+			let ungrab = reader.ungrabber();
+			let release = sink.releaser();
+			Box::new(move || ungrab.iter().chain(release.iter()).for_each(|f| f()))
+		};
+		watchdog::set_rescue(Some(rescue()));
+		let heartbeat = watchdog::Heartbeat::new();
+		let t_watchdog = watchdog::spawn(heartbeat.clone(), rescue());
+
+		let t_in = {
+			let stream = stream.clone();
+			let dropped = Arc::clone(&dropped);
+			thread::spawn(move || {
+				let _finished = heartbeat.finish_on_drop();
+				let mut recorder_behind = false;
+				// broken out of once the output thread has gone
+				'inputs: loop {
+					// wake up in time to keep the mouse moving while its keys are held
+					let timeout = mouse.next_due().map_or(watchdog::TICK, |due| {
+						due.saturating_duration_since(std::time::Instant::now()).min(watchdog::TICK)
+					});
+					let input = match in_rx.recv_timeout(timeout) {
+						Ok(input) => input,
+						Err(RecvTimeoutError::Timeout) => {
+							heartbeat.beat();
+							mouse.sync();
+							continue;
+						}
+						Err(RecvTimeoutError::Disconnected) => break 'inputs,
+					};
+					heartbeat.beat();
+					match input {
+						hid::Input::Analogue(_, _) if routing.bypass.active() => {
+							// evdev is passing everything through, let go of anything we were holding
+							gamepad.centre();
+							mouse.release_all();
+							for (_, mut device) in devices.drain() {
+								for input in &device.releases.complete(vec![], std::time::Instant::now()) {
+									if device.watcher.take_input(input).is_err() {
+										break 'inputs;
+									}
+								}
+							}
 						}
-						let missing_this_time = last_pressed.difference(&pressed);
-						for code in missing_this_time {
-							watcher.take_input(&hid::AnalogueReading {
-								scancode: *code,
-								value: 0.0,
-								ts: std::time::Instant::now(),
-							})
+						hid::Input::Analogue(id, kk) => {
+							let device = devices.entry(id).or_insert_with(|| DeviceWatcher {
+								watcher: watcher::KeyWatcher::new(
+									ev_tx.clone(),
+									id,
+									config.calibration_for(id).clone(),
+									config.resolved_chords.clone(),
+								),
+								releases: watcher::ReleaseTracker::default(),
+							});
+							// releases go with the report they are missing from, so that the
+							// recorder keeps them together
+							let ts = kk.first().map_or_else(std::time::Instant::now, |r| r.ts);
+							for input in &device.releases.complete(kk, ts) {
+								stream.reading(input);
+								if gamepad.take_input(input) && !gamepad.also_type() {
+									continue;
+								}
+								if mouse.take_input(input) && !mouse.also_type() {
+									continue;
+								}
+								if device.watcher.take_input(input).is_err() {
+									break 'inputs;
+								}
+								let midi = &config.output.midi;
+								if midi.aftertouch
+									&& midi.notes.contains_key(&input.scancode)
+									&& ev_tx.send(OutputHidEvent::Pressure(id, input.clone())).is_err()
+								{
+									break 'inputs;
+								}
+								// drop readings rather than hold up typing, or trip the watchdog,
+								// behind a slow disk. Calibrating gives up if any were dropped
+								if record {
+									match record_tx.try_send(input.to_owned()) {
+										Ok(()) => recorder_behind = false,
+										Err(TrySendError::Full(_)) => {
+											dropped.fetch_add(1, Ordering::Relaxed);
+											if !recorder_behind {
+												warn!("recorder can't keep up, dropping readings");
+												recorder_behind = true;
+											}
+										}
+										Err(_) => {}
+									}
+								}
+								//info!("got {code}:{analog}")
+							}
+							gamepad.sync();
+							mouse.sync();
+						}
+						hid::Input::Disconnected(id) => {
+							// release whatever it was holding, and start afresh if it comes back
+							gamepad.centre();
+							mouse.release_all();
+							if let Some(mut device) = devices.remove(&id) {
+								for input in &device.releases.complete(vec![], std::time::Instant::now()) {
+									if device.watcher.take_input(input).is_err() {
+										break 'inputs;
+									}
+								}
+							}
+						}
+						hid::Input::PassThrough(id, evs, ts) => {
+							//info!("got {evs:?}");
+							// chord keys held back on the same keyboard before it were typing,
+							// and go out first
+							let key_down = evs.iter().any(|e| {
+								e.type_ == input_linux::sys::EV_KEY as u16 && e.value == 1
+							});
+							let device = devices.get_mut(&id).filter(|_| key_down);
+							if device.is_some_and(|device| device.watcher.flush_pending().is_err()) {
+								break 'inputs;
+							}
+							if ev_tx.send(OutputHidEvent::Passthrough(evs, ts)).is_err() {
+								break 'inputs;
+							}
+						},
+						hid::Input::Reconfigure(new) => {
+							config = *new;
+							routing.bypass.configure(&config.bypass);
+							routing.shoutable.set(config.shoutable_keys.clone());
+							for (id, device) in devices.iter_mut() {
+								let calibration = config.calibration_for(*id).clone();
+								device.watcher.reconfigure(calibration, config.resolved_chords.clone());
+							}
+							gamepad.reconfigure(&config);
+							mouse.reconfigure(&config);
+							stream.reconfigure(&config.stream);
+							if ev_tx.send(OutputHidEvent::Reconfigure(config.output.clone())).is_err() {
+								break 'inputs;
+							}
+						}
+						hid::Input::Fin() => {
+							return;
 						}
-						last_pressed = pressed;
 					}
-					hid::Input::PassThrough(evs) => {
-						//info!("got {evs:?}");
-						ev_tx.send(OutputHidEvent::Passthrough(evs)).unwrap();
-					},
-					hid::Input::Fin() => {
-						return;
+				}
+				info!("closing in_rx watcher");
+			})
+		};
+
+		let t_out = {
+			thread::spawn(move || {
+				loop {
+					let input = match sink.next_due() {
+						Some(due) => {
+							match ev_rx.recv_timeout(due.saturating_duration_since(std::time::Instant::now())) {
+								Ok(input) => Some(input),
+								Err(RecvTimeoutError::Timeout) => None,
+								Err(RecvTimeoutError::Disconnected) => break,
+							}
+						}
+						None => match ev_rx.recv() {
+							Ok(input) => Some(input),
+							Err(_) => break,
+						},
+					};
+					match input {
+						Some(OutputHidEvent::Key(k)) => {
+							stream.key(&k);
+							sink.send_key(&k);
+						}
+						Some(OutputHidEvent::KeyRelease(device, code, ts)) => {
+							sink.send_key_release(device, code, ts)
+						}
+						Some(OutputHidEvent::Chord(c)) => sink.send_chord(&c),
+						Some(OutputHidEvent::Passthrough(evs, ts)) => sink.send_passthrough(&evs, ts),
+						Some(OutputHidEvent::Pressure(device, r)) => sink.send_pressure(device, &r),
+						Some(OutputHidEvent::Reconfigure(c)) => sink.reconfigure(c),
+						None => {}
 					}
+					sink.flush();
 				}
-			}
-			info!("closing in_rx watcher");
-		})
-	};
+				// let whatever is still queued go out before we close
+				while let Some(due) = sink.next_due() {
+					thread::sleep(due.saturating_duration_since(std::time::Instant::now()));
+					sink.flush();
+				}
+				info!("closing ev_rx watcher");
+			})
+		};
 
-	let t_out = {
-		thread::spawn(move || {
-			let mut outputhid = outputhid::OutputHid::new();
-			for input in ev_rx {
-				match input {
-					OutputHidEvent::Key(k) => outputhid.send_key(&k),
-					OutputHidEvent::Passthrough(evs) => outputhid.send_passthrough(&evs),
+		let mut threads = vec![t_in, t_out, t_stream, t_watchdog];
+		if record {
+			threads.push(thread::spawn(|| {
+				let con = recorder::sqlite_connection().unwrap();
+				let mut recorder = recorder::Recorder::new(&con);
+				for input in record_rx {
+					recorder.record(&input);
 				}
-			}
-			info!("closing ev_rx watcher");
-		})
-	};
+				info!("closing rec_in watcher");
+			}));
+		}
 
-	let rec_in = {
-		thread::spawn(|| {
-			let con = recorder::sqlite_connection().unwrap();
-			let mut recorder = recorder::Recorder::new(&con);
-			for input in record_rx {
-				recorder.record(&input);
-			}
-			info!("closing rec_in watcher");
+		Ok(Pipeline {
+			reader,
+			hid_tx,
+			threads,
+			dropped,
 		})
-	};
-
-	t_in.join().unwrap();
+	}
 
-	t_out.join().unwrap();
+	/// Shut the pipeline down now.
+	fn stop(self) {
+		let _ = self.hid_tx.send(hid::Input::Fin());
+		self.join();
+	}
 
-	rec_in.join().unwrap();
+	/// Wait for the pipeline to be sent `hid::Input::Fin`, then shut it down.
+	fn join(mut self) {
+		for t in self.threads.drain(..) {
+			// a panicking thread has already been logged, carry on shutting the rest down
+			let _ = t.join();
+		}
 
-	info!("closing main");
-	reader.unload();
+		info!("closing main");
+		watchdog::set_rescue(None);
+		self.reader.stop();
+	}
 }
 
 
 pub enum OutputHidEvent {
 	Key(watcher::KeyEvent),
-	Passthrough(Vec<input_linux::sys::input_event>)
+	/// A key let go of, and when the reading that let go of it was taken
+	KeyRelease(DeviceID, u16, std::time::Instant),
+	Chord(watcher::ChordEvent),
+	/// Events from evdev, and when they were read
+	Passthrough(Vec<input_linux::sys::input_event>, std::time::Instant),
+	/// How far down a MIDI key is, for aftertouch
+	Pressure(DeviceID, hid::AnalogueReading),
+	Reconfigure(config::OutputConfig),
 }
 
 // rakers
 // anvil
 // laceys
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use sink::testing::Buffer;
+
+	/// A file under the temp directory with `text` in it, named for `test`.
+	fn temp_file(test: &str, text: &str) -> PathBuf {
+		let name = format!("wooting-shouting-{}-{test}", std::process::id());
+		let path = std::env::temp_dir().join(name);
+		std::fs::write(&path, text).unwrap();
+		path
+	}
+
+	/// `toml` loaded as the config file, for `test`.
+	fn config(test: &str, toml: &str) -> config::Config {
+		let path = temp_file(&format!("{test}.toml"), toml);
+		let (config, _) = config::load(Some(&path), &config::Overrides::default()).unwrap();
+		std::fs::remove_file(path).unwrap();
+		config
+	}
+
+	/// Play `source` through a whole pipeline and return the text it typed.
+	fn typed(config: &config::Config, source: &simulated::Source) -> String {
+		typed_by(config, simulated::SimulatedDevice::from_source(source).unwrap())
+	}
+
+	fn typed_by(config: &config::Config, device: simulated::SimulatedDevice) -> String {
+		let out = Buffer::default();
+		let sink = Box::new(sink::TextSink::new(Box::new(out.clone()), config.output.clone()));
+		let pipeline = Pipeline::start(
+			config,
+			Box::new(device),
+			wrap_sink(sink, config.output.clone(), false).unwrap(),
+			gamepad::Gamepad::new(config, false).unwrap(),
+			mouse::Mouse::new(config, false).unwrap(),
+			false,
+		)
+		.unwrap();
+		pipeline.join();
+		out.text()
+	}
+
+	fn script(test: &str, toml: &str, script: &str) -> String {
+		let path = temp_file(&format!("{test}.script"), script);
+		let text = typed(&config(test, toml), &simulated::Source::Script(path.clone()));
+		std::fs::remove_file(path).unwrap();
+		text
+	}
+
+	#[test]
+	fn types_text_shouting_only_the_hard_presses() {
+		let text = "Hello there, World.";
+		let source = simulated::Source::Text(text.to_string());
+		assert_eq!(typed(&config("text", ""), &source), text);
+	}
+
+	#[test]
+	fn hard_press_shouts() {
+		let typed = script(
+			"hard",
+			"",
+			"
+			0   A 0.2
+			20  A 0.6
+			40  A 1.0
+			60  A 0
+			100 B 0.5
+			102 B 1.0
+			130 B 0
+			",
+		);
+		assert_eq!(typed, "aB");
+	}
+
+	#[test]
+	fn replays_sessions_recorded_without_releases() {
+		let event = |ts: f64, key: &str, value: f32| recorder::RecordedEvent {
+			ts,
+			key: key.to_string(),
+			value,
+		};
+		// a hard A then a soft B, which are never seen going back up
+		let events = [
+			event(0.0, "A", 0.5),
+			event(0.002, "A", 1.0),
+			event(0.1, "B", 0.2),
+			event(0.11, "B", 0.6),
+			event(0.12, "B", 1.0),
+		];
+		let device = simulated::SimulatedDevice::new(simulated::recorded_movements(&events));
+		assert_eq!(typed_by(&config("session", ""), device), "Ab");
+	}
+
+	#[test]
+	fn passthrough_keys_keep_their_place() {
+		let typed = script(
+			"passthrough",
+			"",
+			"
+			0   A     0.2
+			20  A     0.6
+			40  A     1.0
+			60  A     0
+			100 Space 1
+			130 Space 0
+			200 B     0.5
+			202 B     1.0
+			230 B     0
+			",
+		);
+		assert_eq!(typed, "a B");
+	}
+
+	#[test]
+	fn chord_keys_go_out_before_passthrough_keys() {
+		let typed = script(
+			"chord-passthrough",
+			"[chords]\nkeys = [\"C\"]\n",
+			"
+			0   C     0.2
+			20  C     0.6
+			40  C     0.93
+			100 Space 1
+			130 Space 0
+			150 C     0
+			",
+		);
+		assert_eq!(typed, "c ");
+	}
+
+	#[test]
+	fn device_that_does_not_shout() {
+		let config = config("device", "[devices.0x0]\nshout = false\n");
+		let source = simulated::Source::Text("Hello".to_string());
+		assert_eq!(typed(&config, &source), "hello");
+	}
+
+	#[test]
+	fn tiers_only_for_presses_that_shout() {
+		let tier = "[[output.tiers]]\nvelocity = 100.0\nappend = \"!\"\n";
+		let source = simulated::Source::Text("Hello there".to_string());
+		assert_eq!(typed(&config("tier", tier), &source), "Hello! there");
+		let quiet = format!("{tier}[devices.0x0]\nshout = false\n");
+		assert_eq!(typed(&config("quiet-tier", &quiet), &source), "hello there");
+	}
+
+	#[test]
+	fn sentence_case_does_not_reach_tiers() {
+		let case =
+			"[output]\nsentence_case = true\n[[output.tiers]]\nvelocity = 100.0\nappend = \"*\"\n";
+		let source = simulated::Source::Text("hi. there, Big end".to_string());
+		assert_eq!(
+			typed(&config("case-tier", case), &source),
+			"hi. There, Big* end"
+		);
+		let quiet = format!("{case}shout = false\n");
+		assert_eq!(
+			typed(&config("case-quiet-tier", &quiet), &source),
+			"hi. There, big* end"
+		);
+		let device = format!("{case}[devices.0x0]\nshout = false\n");
+		assert_eq!(
+			typed(&config("case-device", &device), &source),
+			"hi. There, big end"
+		);
+	}
+
+	#[test]
+	fn retypes_words_unless_a_letter_repeats() {
+		let retype = "[output.words]\nmode = \"retype\"\n";
+		let word = |hold: u32| {
+			format!(
+				"
+				0   A 0.2
+				20  A 0.6
+				40  A 1.0
+				60  A 0
+				100 B 0.5
+				102 B 1.0
+				{} B 0
+				",
+				102 + hold
+			)
+		};
+		assert_eq!(script("retype", retype, &word(30)), "aB\u{8}\u{8}AB");
+		assert_eq!(script("repeat", retype, &word(400)), "aB");
+	}
+}
@@ -0,0 +1,303 @@
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::time::Instant;
+
+use alsa::seq;
+use anyhow::Context;
+use wooting_analog_plugin_dev::wooting_analog_common::DeviceID;
+
+use crate::config::{MidiConfig, OutputConfig};
+use crate::watcher::{ChordEvent, KeyEvent};
+use crate::{hid, sink::OutputSink};
+
+const CLIENT_NAME: &str = "Wooting SHOUTING";
+const PORT_NAME: &str = "Wooting SHOUTING notes";
+
+/// A note being played.
+struct Note {
+	note: u8,
+	/// The aftertouch last sent for it
+	pressure: u8,
+}
+
+/// An ALSA sequencer client with one port that anything can subscribe to, e.g. with
+/// `aconnect` or a synth's MIDI input settings.
+struct Port {
+	seq: seq::Seq,
+	port: i32,
+}
+
+impl Port {
+	fn open() -> anyhow::Result<Self> {
+		let seq = seq::Seq::open(None, Some(alsa::Direction::Playback), false)
+			.context("couldn't open the ALSA sequencer")?;
+		seq.set_client_name(&CString::new(CLIENT_NAME)?)?;
+		let port = seq
+			.create_simple_port(
+				&CString::new(PORT_NAME)?,
+				seq::PortCap::READ | seq::PortCap::SUBS_READ,
+				seq::PortType::MIDI_GENERIC | seq::PortType::APPLICATION,
+			)
+			.context("couldn't create the MIDI port")?;
+		log::info!("created MIDI port {}:{port}", seq.client_id()?);
+		Ok(Port { seq, port })
+	}
+
+	fn send(&self, event_type: seq::EventType, note: seq::EvNote) {
+		let mut event = seq::Event::new(event_type, &note);
+		event.set_source(self.port);
+		event.set_subs();
+		event.set_direct();
+		if let Err(e) = self.seq.event_output_direct(&mut event) {
+			log::error!("couldn't send MIDI {event_type:?}: {e}");
+		}
+	}
+}
+
+/// Plays the keys in `config.midi` as notes, in front of the sink that does the typing.
+///
+/// A press plays its note at a velocity from how fast it went down, and its release stops it.
+/// While it is held, how far down it is goes out as polyphonic aftertouch. Unless
+/// `config.midi.also_type`, the keys don't reach the inner sink at all.
+pub struct MidiSink {
+	inner: Box<dyn OutputSink>,
+	config: MidiConfig,
+	virtual_device: bool,
+	port: Option<Port>,
+	/// Notes being played, by the keyboard and key playing them
+	playing: HashMap<(DeviceID, u16), Note>,
+}
+
+impl MidiSink {
+	pub fn new(
+		inner: Box<dyn OutputSink>,
+		config: OutputConfig,
+		virtual_device: bool,
+	) -> anyhow::Result<Self> {
+		let mut sink = MidiSink {
+			inner,
+			config: config.midi,
+			virtual_device,
+			port: None,
+			playing: HashMap::new(),
+		};
+		sink.open()?;
+		Ok(sink)
+	}
+
+	/// Open the sequencer port once a key is mapped to a note.
+	fn open(&mut self) -> anyhow::Result<()> {
+		if !self.virtual_device || self.config.notes.is_empty() || self.port.is_some() {
+			return Ok(());
+		}
+		self.port = Some(Port::open()?);
+		Ok(())
+	}
+
+	/// Send `note` with `value` as its velocity, or its pressure for aftertouch.
+	fn send(&self, event_type: seq::EventType, note: u8, value: u8) {
+		let Some(port) = &self.port else {
+			log::debug!("MIDI {event_type:?} note {note} value {value}");
+			return;
+		};
+		port.send(
+			event_type,
+			seq::EvNote {
+				channel: self.config.channel - 1,
+				note,
+				velocity: value,
+				off_velocity: 0,
+				duration: 0,
+			},
+		);
+	}
+
+	fn stop_all(&mut self) {
+		for (_, playing) in std::mem::take(&mut self.playing) {
+			self.send(seq::EventType::Noteoff, playing.note, 0);
+		}
+	}
+}
+
+impl OutputSink for MidiSink {
+	fn send_key(&mut self, k: &KeyEvent) {
+		let Some(&note) = self.config.notes.get(&k.scancode) else {
+			return self.inner.send_key(k);
+		};
+		// a press we haven't seen the release of, e.g. from before a reconfigure
+		if let Some(playing) = self.playing.remove(&(k.device, k.scancode)) {
+			self.send(seq::EventType::Noteoff, playing.note, 0);
+		}
+		let velocity = self.config.note_velocity(k.velocity);
+		self.send(seq::EventType::Noteon, note, velocity);
+		self.playing.insert((k.device, k.scancode), Note { note, pressure: 0 });
+		if self.config.also_type {
+			self.inner.send_key(k);
+		}
+	}
+
+	fn send_key_release(&mut self, device: DeviceID, code: u16, ts: Instant) {
+		if let Some(playing) = self.playing.remove(&(device, code)) {
+			self.send(seq::EventType::Noteoff, playing.note, 0);
+		}
+		if self.config.also_type || !self.config.notes.contains_key(&code) {
+			self.inner.send_key_release(device, code, ts);
+		}
+	}
+
+	fn send_chord(&mut self, c: &ChordEvent) {
+		self.inner.send_chord(c);
+	}
+
+	fn send_passthrough(&mut self, evs: &[input_linux::sys::input_event], ts: Instant) {
+		self.inner.send_passthrough(evs, ts);
+	}
+
+	fn send_text(&mut self, text: &str, ts: Instant) {
+		self.inner.send_text(text, ts);
+	}
+
+	fn send_pressure(&mut self, device: DeviceID, reading: &hid::AnalogueReading) {
+		if !self.config.aftertouch {
+			return;
+		}
+		let Some(playing) = self.playing.get_mut(&(device, reading.scancode)) else {
+			return;
+		};
+		let pressure = (reading.value.clamp(0.0, 1.0) * 127.0).round() as u8;
+		if pressure == playing.pressure {
+			return;
+		}
+		playing.pressure = pressure;
+		let note = playing.note;
+		self.send(seq::EventType::Keypress, note, pressure);
+	}
+
+	fn reconfigure(&mut self, config: OutputConfig) {
+		self.stop_all();
+		self.config = config.midi.clone();
+		if let Err(e) = self.open() {
+			log::error!("{e:#}");
+		}
+		self.inner.reconfigure(config);
+	}
+
+	fn releaser(&self) -> Option<Box<dyn Fn() + Send>> {
+		self.inner.releaser()
+	}
+
+	fn next_due(&self) -> Option<Instant> {
+		self.inner.next_due()
+	}
+
+	fn flush(&mut self) {
+		self.inner.flush();
+	}
+}
+
+impl Drop for MidiSink {
+	fn drop(&mut self) {
+		self.stop_all();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::sink::{testing::Buffer, TextSink};
+
+	use super::*;
+
+	const A: u16 = 30;
+	const B: u16 = 48;
+
+	/// A sink without a sequencer port playing A as middle C, typing into `Buffer`.
+	fn midi_sink(midi: &str) -> (MidiSink, Buffer) {
+		let mut config = OutputConfig {
+			midi: toml::from_str(midi).unwrap(),
+			..Default::default()
+		};
+		config.midi.notes = HashMap::from([(A, 60)]);
+		let out = Buffer::default();
+		let text = TextSink::new(Box::new(out.clone()), config.clone());
+		(MidiSink::new(Box::new(text), config, false).unwrap(), out)
+	}
+
+	fn press(scancode: u16, velocity: f32) -> KeyEvent {
+		KeyEvent {
+			device: DeviceID::default(),
+			scancode,
+			caps: false,
+			capitalise: false,
+			velocity,
+			ts: Instant::now(),
+		}
+	}
+
+	fn pressure(sink: &MidiSink) -> Option<u8> {
+		sink.playing
+			.get(&(DeviceID::default(), A))
+			.map(|note| note.pressure)
+	}
+
+	#[test]
+	fn plays_mapped_keys_instead_of_typing_them() {
+		let (mut sink, out) = midi_sink("");
+		sink.send_key(&press(A, 100.0));
+		sink.send_key(&press(B, 100.0));
+		assert_eq!(pressure(&sink), Some(0));
+		assert_eq!(out.text(), "b");
+
+		sink.send_key_release(DeviceID::default(), A, Instant::now());
+		assert!(sink.playing.is_empty());
+	}
+
+	#[test]
+	fn also_types_when_asked() {
+		let (mut sink, out) = midi_sink("also_type = true");
+		sink.send_key(&press(A, 100.0));
+		assert_eq!(pressure(&sink), Some(0));
+		assert_eq!(out.text(), "a");
+	}
+
+	#[test]
+	fn scales_velocity() {
+		let (sink, _) = midi_sink("max_velocity = 400.0\ncurve = 2.0");
+		assert_eq!(sink.config.note_velocity(0.0), 1);
+		assert_eq!(sink.config.note_velocity(200.0), 33);
+		assert_eq!(sink.config.note_velocity(400.0), 127);
+		assert_eq!(sink.config.note_velocity(1000.0), 127);
+	}
+
+	#[test]
+	fn sends_aftertouch_while_held() {
+		let reading = |scancode: u16, value: f32| hid::AnalogueReading {
+			scancode,
+			value,
+			ts: Instant::now(),
+		};
+		let (mut sink, _) = midi_sink("");
+		sink.send_pressure(DeviceID::default(), &reading(A, 0.5));
+		assert_eq!(pressure(&sink), None);
+
+		sink.send_key(&press(A, 100.0));
+		sink.send_pressure(DeviceID::default(), &reading(A, 0.5));
+		assert_eq!(pressure(&sink), Some(64));
+		sink.send_pressure(DeviceID::default(), &reading(A, 1.2));
+		assert_eq!(pressure(&sink), Some(127));
+		sink.send_pressure(DeviceID::default(), &reading(B, 0.5));
+		assert_eq!(sink.playing.len(), 1);
+
+		let (mut sink, _) = midi_sink("aftertouch = false");
+		sink.send_key(&press(A, 100.0));
+		sink.send_pressure(DeviceID::default(), &reading(A, 0.5));
+		assert_eq!(pressure(&sink), Some(0));
+	}
+
+	#[test]
+	fn stops_every_note_on_reconfigure() {
+		let (mut sink, _) = midi_sink("");
+		sink.send_key(&press(A, 100.0));
+		sink.reconfigure(OutputConfig::default());
+		assert!(sink.playing.is_empty());
+	}
+}
@@ -0,0 +1,318 @@
+use std::collections::{BTreeSet, HashMap};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use input_linux::{sys, uinput, Key, RelativeAxis};
+
+use crate::config::{Config, MouseConfig, MouseInput};
+use crate::hid;
+
+/// How often the pointer moves while a key is held, even if the keyboard has nothing new to report
+pub const MOVE_INTERVAL: Duration = Duration::from_millis(8);
+
+/// The axis `input` moves, and which way, or `None` for a click.
+fn axis(input: MouseInput) -> Option<(RelativeAxis, f32)> {
+	use MouseInput::*;
+	match input {
+		Left => Some((RelativeAxis::X, -1.0)),
+		Right => Some((RelativeAxis::X, 1.0)),
+		Up => Some((RelativeAxis::Y, -1.0)),
+		Down => Some((RelativeAxis::Y, 1.0)),
+		ScrollUp => Some((RelativeAxis::Wheel, 1.0)),
+		ScrollDown => Some((RelativeAxis::Wheel, -1.0)),
+		LeftClick | RightClick | MiddleClick => None,
+	}
+}
+
+/// The button `input` clicks, if it is a click.
+fn button(input: MouseInput) -> Option<Key> {
+	match input {
+		MouseInput::LeftClick => Some(Key::ButtonLeft),
+		MouseInput::RightClick => Some(Key::ButtonRight),
+		MouseInput::MiddleClick => Some(Key::ButtonMiddle),
+		_ => None,
+	}
+}
+
+/// A virtual mouse whose pointer and wheel move as fast as the `[mouse]` keys are pressed deep,
+/// and whose buttons are down while their keys are past `click_depth`.
+///
+/// Movement is worked out from the time since it last moved, so it keeps the same speed however
+/// often the keyboard reports. Fractions of a pixel carry over to the next move.
+pub struct Mouse {
+	config: MouseConfig,
+	keys: HashMap<u16, MouseInput>,
+	virtual_device: bool,
+	handle: Option<uinput::UInputHandle<std::fs::File>>,
+	/// How far down each mapped key is, from 0.0 to 1.0
+	depths: HashMap<u16, f32>,
+	/// Buttons last pressed
+	buttons: BTreeSet<u16>,
+	/// When the pointer or wheel last moved, while there is something moving them
+	last_move: Option<Instant>,
+	/// What is left over of the last moves along X, Y and the wheel
+	remainder: [f32; 3],
+}
+
+impl Mouse {
+	pub fn new(config: &Config, virtual_device: bool) -> anyhow::Result<Self> {
+		let mut mouse = Mouse {
+			config: config.mouse.clone(),
+			keys: config.mouse_keys.clone(),
+			virtual_device,
+			handle: None,
+			depths: HashMap::new(),
+			buttons: BTreeSet::new(),
+			last_move: None,
+			remainder: [0.0; 3],
+		};
+		mouse.open()?;
+		Ok(mouse)
+	}
+
+	/// Create the uinput device once a key is mapped to the mouse.
+	fn open(&mut self) -> anyhow::Result<()> {
+		use std::os::unix::fs::OpenOptionsExt;
+
+		if !self.virtual_device || self.keys.is_empty() || self.handle.is_some() {
+			return Ok(());
+		}
+		let file = std::fs::OpenOptions::new()
+			.write(true)
+			.custom_flags(libc::O_NONBLOCK)
+			.open("/dev/uinput")
+			.context("couldn't open /dev/uinput for the mouse")?;
+		let handle = uinput::UInputHandle::new(file);
+
+		handle.set_evbit(input_linux::EventKind::Relative)?;
+		handle.set_evbit(input_linux::EventKind::Key)?;
+		handle.set_evbit(input_linux::EventKind::Synchronize)?;
+		for axis in [RelativeAxis::X, RelativeAxis::Y, RelativeAxis::Wheel] {
+			handle.set_relbit(axis)?;
+		}
+		// libinput only treats it as a mouse if it has buttons, whether or not any are mapped
+		for button in [Key::ButtonLeft, Key::ButtonRight, Key::ButtonMiddle] {
+			handle.set_keybit(button)?;
+		}
+
+		let input_id = input_linux::InputId {
+			bustype: sys::BUS_USB,
+			vendor: 0x4711,
+			product: 0x0817,
+			version: 0,
+		};
+		handle
+			.create(&input_id, b"Wooting SHOUTING mouse", 0, &[])
+			.context("couldn't create the mouse device")?;
+		log::info!("created virtual mouse for {} keys", self.keys.len());
+		self.handle = Some(handle);
+		Ok(())
+	}
+
+	pub fn reconfigure(&mut self, config: &Config) {
+		self.release_all();
+		self.config = config.mouse.clone();
+		self.keys = config.mouse_keys.clone();
+		if let Err(e) = self.open() {
+			log::error!("{e:#}");
+		}
+	}
+
+	/// Whether keys mapped to the mouse should type as well.
+	pub fn also_type(&self) -> bool {
+		self.config.also_type
+	}
+
+	/// Take `reading` if it is for a mapped key, returning whether it was. Nothing moves until
+	/// `sync`.
+	pub fn take_input(&mut self, reading: &hid::AnalogueReading) -> bool {
+		if !self.keys.contains_key(&reading.scancode) {
+			return false;
+		}
+		self.depths.insert(reading.scancode, reading.value);
+		true
+	}
+
+	/// When `sync` should next be called to keep the pointer moving, if anything is moving it.
+	pub fn next_due(&self) -> Option<Instant> {
+		self.last_move.map(|last| last + MOVE_INTERVAL)
+	}
+
+	/// Let go of every key, stopping the pointer and releasing the buttons.
+	pub fn release_all(&mut self) {
+		self.depths.clear();
+		self.sync();
+		self.remainder = [0.0; 3];
+	}
+
+	/// Move the pointer and wheel for the time since they last moved, and press or release any
+	/// buttons that have changed.
+	pub fn sync(&mut self) {
+		let mut events = self.events_until(Instant::now());
+		if events.is_empty() {
+			return;
+		}
+
+		let Some(handle) = &self.handle else {
+			for e in events.iter() {
+				log::debug!("mouse event type {} code {} value {}", e.type_, e.code, e.value);
+			}
+			return;
+		};
+		events.push(sys::input_event {
+			time: libc::timeval {
+				tv_sec: 0,
+				tv_usec: 0,
+			},
+			type_: sys::EV_SYN as u16,
+			code: sys::SYN_REPORT as u16,
+			value: 0,
+		});
+		if let Err(e) = handle.write(&events) {
+			log::error!("couldn't move the mouse: {e}");
+		}
+	}
+
+	/// The moves and clicks `sync` makes at `now`, without the report that ends them.
+	fn events_until(&mut self, now: Instant) -> Vec<sys::input_event> {
+		let elapsed = self
+			.last_move
+			.map_or(0.0, |last| now.saturating_duration_since(last).as_secs_f32());
+
+		let mut speeds = [0.0f32; 3];
+		let mut buttons = BTreeSet::new();
+		for (scancode, input) in self.keys.iter() {
+			let depth = self.depths.get(scancode).copied().unwrap_or_default();
+			if let Some(button) = button(*input) {
+				if depth >= self.config.click_depth {
+					buttons.insert(u16::from(button));
+				}
+				continue;
+			}
+			let Some((axis, direction)) = axis(*input) else {
+				continue;
+			};
+			let (i, speed) = match axis {
+				RelativeAxis::X => (0, self.config.speed),
+				RelativeAxis::Y => (1, self.config.speed),
+				_ => (2, self.config.scroll_speed),
+			};
+			speeds[i] += direction * speed * self.config.response(depth);
+		}
+		let moving = speeds.iter().any(|speed| *speed != 0.0);
+		self.last_move = moving.then_some(now);
+
+		let time = libc::timeval {
+			tv_sec: 0,
+			tv_usec: 0,
+		};
+		let event = |type_: i32, code: u16, value: i32| sys::input_event {
+			time,
+			type_: type_ as u16,
+			code,
+			value,
+		};
+		let mut events = vec![];
+		let axes = [RelativeAxis::X, RelativeAxis::Y, RelativeAxis::Wheel];
+		for (i, axis) in axes.into_iter().enumerate() {
+			let distance = self.remainder[i] + speeds[i] * elapsed;
+			let whole = distance.trunc();
+			self.remainder[i] = if speeds[i] == 0.0 { 0.0 } else { distance - whole };
+			if whole != 0.0 {
+				events.push(event(sys::EV_REL, axis as u16, whole as i32));
+			}
+		}
+		for code in self.buttons.symmetric_difference(&buttons) {
+			events.push(event(sys::EV_KEY, *code, buttons.contains(code) as i32));
+		}
+		self.buttons = buttons;
+		events
+	}
+}
+
+impl Drop for Mouse {
+	fn drop(&mut self) {
+		self.release_all();
+		if let Some(handle) = &self.handle {
+			if let Err(e) = handle.dev_destroy() {
+				log::error!("couldn't destroy the mouse device: {e}");
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// A mouse without a uinput device, for `keys` in `[mouse.keys]` and a linear response.
+	fn mouse(keys: &str) -> Mouse {
+		let toml = format!(
+			"[mouse]\nspeed = 1000.0\nscroll_speed = 40.0\ndeadzone = 0.0\ncurve = 1.0\n\
+			 click_depth = 0.5\n[mouse.keys]\n{keys}"
+		);
+		let mut config: Config = toml::from_str(&toml).unwrap();
+		config.mouse_keys = config.mouse.scancodes().unwrap();
+		Mouse::new(&config, false).unwrap()
+	}
+
+	fn press(mouse: &mut Mouse, key: &str, value: f32) -> bool {
+		let reading = hid::AnalogueReading {
+			scancode: crate::keycode::scancode_from_name(key).unwrap(),
+			value,
+			ts: Instant::now(),
+		};
+		mouse.take_input(&reading)
+	}
+
+	/// `(type, code, value)` of each event `mouse` makes at `now`.
+	fn events(mouse: &mut Mouse, now: Instant) -> Vec<(i32, u16, i32)> {
+		mouse
+			.events_until(now)
+			.iter()
+			.map(|e| (e.type_ as i32, e.code, e.value))
+			.collect()
+	}
+
+	#[test]
+	fn moves_as_fast_as_keys_are_pressed() {
+		let mut mouse = mouse("A = \"left\"\nS = \"scroll_up\"\n");
+		let start = Instant::now();
+		assert!(press(&mut mouse, "A", 0.5));
+		assert!(!press(&mut mouse, "D", 1.0));
+		assert_eq!(events(&mut mouse, start), []);
+		assert_eq!(mouse.next_due(), Some(start + MOVE_INTERVAL));
+
+		let x = RelativeAxis::X as u16;
+		let later = start + Duration::from_millis(125);
+		assert_eq!(events(&mut mouse, later), [(sys::EV_REL, x, -62)]);
+		// the half pixel left over carries on to the next move
+		let later = later + Duration::from_millis(125);
+		assert_eq!(events(&mut mouse, later), [(sys::EV_REL, x, -63)]);
+
+		press(&mut mouse, "A", 0.0);
+		press(&mut mouse, "S", 1.0);
+		let wheel = RelativeAxis::Wheel as u16;
+		let later = later + Duration::from_millis(250);
+		assert_eq!(events(&mut mouse, later), [(sys::EV_REL, wheel, 10)]);
+
+		mouse.release_all();
+		assert_eq!(mouse.next_due(), None);
+	}
+
+	#[test]
+	fn clicks_past_click_depth() {
+		let mut mouse = mouse("Space = \"left_click\"\n");
+		let button = u16::from(Key::ButtonLeft);
+		// nothing moves, so the time doesn't matter
+		let now = Instant::now();
+		press(&mut mouse, "Space", 0.4);
+		assert_eq!(events(&mut mouse, now), []);
+		press(&mut mouse, "Space", 0.6);
+		assert_eq!(events(&mut mouse, now), [(sys::EV_KEY, button, 1)]);
+		assert_eq!(events(&mut mouse, now), []);
+		press(&mut mouse, "Space", 0.0);
+		assert_eq!(events(&mut mouse, now), [(sys::EV_KEY, button, 0)]);
+		assert_eq!(mouse.next_due(), None);
+	}
+}
@@ -1,16 +1,124 @@
-use std::time::Duration;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
 
 use input_linux::{uinput, InputEvent};
 
-use crate::{hid, watcher::KeyEvent};
+use anyhow::Context;
+use wooting_analog_plugin_dev::wooting_analog_common::DeviceID;
 
+use crate::watcher::{ChordEvent, KeyEvent};
+use crate::{config::OutputConfig, layout::Layout, sink::OutputSink};
+
+/// Value of an `EV_KEY` event generated by autorepeat
+const KEY_REPEAT: i32 = 2;
+/// How long a key is held on our device before the kernel autorepeats it. We don't set one, so
+/// this is the kernel's default
+pub const REPEAT_DELAY: Duration = Duration::from_millis(250);
+/// How many injections to average over for each latency summary in the log
+const LATENCY_REPORT_EVERY: u32 = 500;
+
+const SHIFT_KEYS: [input_linux::Key; 2] = [input_linux::Key::LeftShift, input_linux::Key::RightShift];
+
+/// Shift and Caps Lock state, as seen in the passthrough event stream.
+#[derive(Default)]
+pub struct Modifiers {
+	/// Shift keys the user is physically holding
+	pub shift: HashSet<u16>,
+	pub caps_lock: bool,
+}
+
+impl Modifiers {
+	pub fn observe(&mut self, e: &input_linux::sys::input_event) {
+		if e.type_ == input_linux::sys::EV_LED as u16 && e.code == input_linux::sys::LED_CAPSL as u16 {
+			self.caps_lock = e.value != 0;
+			return;
+		}
+		if e.type_ != input_linux::sys::EV_KEY as u16 || e.value == KEY_REPEAT {
+			return;
+		}
+		if SHIFT_KEYS.iter().any(|k| u16::from(*k) == e.code) {
+			if e.value == 0 {
+				self.shift.remove(&e.code);
+			} else {
+				self.shift.insert(e.code);
+			}
+		} else if e.code == u16::from(input_linux::Key::CapsLock) && e.value == 1 {
+			self.caps_lock = !self.caps_lock;
+		}
+	}
+
+	/// Whether `scancode` would come out shifted on `layout` with nothing of ours in the way.
+	pub fn upper(&self, layout: Layout, scancode: u16) -> bool {
+		!self.shift.is_empty() ^ (self.caps_lock && layout.is_letter(scancode))
+	}
+}
+
+/// Events waiting in `OutputHid`'s queue to be written to the device.
+struct Scheduled {
+	due: Instant,
+	/// When the input behind these events was read, for latency reporting
+	since: Instant,
+	events: Vec<input_linux::sys::input_event>,
+}
+
+#[derive(Default)]
+struct LatencyStats {
+	count: u32,
+	total: Duration,
+	max: Duration,
+}
+
+impl LatencyStats {
+	fn add(&mut self, latency: Duration) {
+		self.count += 1;
+		self.total += latency;
+		self.max = self.max.max(latency);
+		if self.count >= LATENCY_REPORT_EVERY {
+			log::info!(
+				"injection latency over the last {} events: mean {:?}, max {:?}",
+				self.count,
+				self.total / self.count,
+				self.max
+			);
+			*self = LatencyStats::default();
+		}
+	}
+}
+
+/// Turns key and passthrough events into writes to a uinput keyboard.
+///
+/// Nothing here blocks: events are planned in the order they arrive, timed relative to the ones
+/// before them, and queued. The output thread calls `flush` when `next_due` comes around.
 pub struct OutputHid {
 	handle: input_linux::uinput::UInputHandle<std::fs::File>,
-	epoch: std::time::Instant
+	epoch: std::time::Instant,
+	config: OutputConfig,
+	queue: VecDeque<Scheduled>,
+	latency: LatencyStats,
+	modifiers: Modifiers,
+	/// Shift keys currently down on our device, which differs from `modifiers.shift` while we
+	/// override it
+	device_shift: HashSet<u16>,
+	/// Keys from `send_key` that haven't been released yet, by the keyboard holding them. The
+	/// same key can be down on more than one
+	held: HashSet<(DeviceID, u16)>,
+	/// Held keys that needed a different Shift from what the user is holding
+	overridden: HashSet<(DeviceID, u16)>,
+	/// Keys passed through that are down on our device
+	passthrough_held: HashSet<u16>,
+	/// Held keys that reached one of `config.tiers` or were chords, with what is left to do on
+	/// release
+	extras: HashMap<(DeviceID, u16), Extras>,
+}
+
+/// What is left to do when letting go of a key from a `config::Tier` or a chord.
+struct Extras {
+	/// Modifiers we pressed, to let go of along with the key
+	modifiers: Vec<u16>,
 }
 
 impl OutputHid {
-	pub fn new() -> Self {
+	pub fn new(config: OutputConfig) -> anyhow::Result<Self> {
 		use std::os::unix::fs::OpenOptionsExt;
 
 		let epoch = std::time::Instant::now();
@@ -20,19 +128,21 @@ impl OutputHid {
 			.write(true)
 			.custom_flags(libc::O_NONBLOCK)
 			.open("/dev/uinput")
-			.expect("erro opening /dev/uinput");
+			.context("couldn't open /dev/uinput, try --output-to - to print what would be typed instead")?;
 
 		let handle = uinput::UInputHandle::new(uinput_file);
 
-		handle.set_evbit(input_linux::EventKind::Key).unwrap();
-		handle
-			.set_evbit(input_linux::EventKind::Synchronize)
-			.unwrap();
+		handle.set_evbit(input_linux::EventKind::Key)?;
+		handle.set_evbit(input_linux::EventKind::Synchronize)?;
+		// let the kernel autorepeat held keys for us, the same as it would for a real keyboard
+		handle.set_evbit(input_linux::EventKind::Autorepeat)?;
+		// so that whoever owns the keyboard LEDs tells us about Caps Lock
+		handle.set_evbit(input_linux::EventKind::Led)?;
+		handle.set_ledbit(input_linux::LedKind::CapsLock)?;
 
 		for k in 0..248 {
-			handle
-				.set_keybit(input_linux::Key::from_code(k).unwrap())
-				.unwrap();
+			let key = input_linux::Key::from_code(k).with_context(|| format!("no key with code {k}"))?;
+			handle.set_keybit(key)?;
 		}
 
 		let input_id = input_linux::InputId {
@@ -43,122 +153,403 @@ impl OutputHid {
 		};
 		let device_name = b"Wooting SHOUTING";
 
-		handle.create(&input_id, device_name, 0, &[]).unwrap();
+		handle
+			.create(&input_id, device_name, 0, &[])
+			.context("couldn't create the virtual keyboard")?;
+
+		Ok(OutputHid {
+			handle,
+			epoch,
+			config,
+			queue: VecDeque::new(),
+			latency: LatencyStats::default(),
+			modifiers: Modifiers::default(),
+			device_shift: HashSet::new(),
+			held: HashSet::new(),
+			overridden: HashSet::new(),
+			passthrough_held: HashSet::new(),
+			extras: HashMap::new(),
+		})
+	}
+
+	/// A key event and the report that goes with it. Timestamps are filled in by `flush`.
+	fn key_events(key: input_linux::Key, state: input_linux::KeyState) -> Vec<input_linux::sys::input_event> {
+		let time = input_linux::EventTime::new(0, 0);
+		vec![
+			*input_linux::InputEvent::from(input_linux::KeyEvent::new(time, key, state)).as_raw(),
+			*input_linux::InputEvent::from(input_linux::SynchronizeEvent::new(
+				time,
+				input_linux::SynchronizeKind::Report,
+				0,
+			))
+			.as_raw(),
+		]
+	}
+
+	/// Queue `events` to go out `delay` after whatever is already queued, or after now if nothing is.
+	fn schedule(&mut self, delay: Duration, since: Instant, events: Vec<input_linux::sys::input_event>) {
+		if events.is_empty() {
+			return;
+		}
+		let after = match self.queue.back() {
+			Some(last) => last.due.max(Instant::now()),
+			None => Instant::now(),
+		};
+		self.queue.push_back(Scheduled {
+			due: after + delay,
+			since,
+			events,
+		});
+	}
+
+	/// Pick up any LED changes written to our device since we last looked.
+	fn read_leds(&mut self) {
+		let mut buf = [input_linux::sys::input_event {
+			time: libc::timeval {
+				tv_sec: 0,
+				tv_usec: 0,
+			},
+			type_: 0,
+			code: 0,
+			value: 0,
+		}; 16];
+		while let Ok(len) = self.handle.read(&mut buf) {
+			if len == 0 {
+				break;
+			}
+			for e in &buf[..len] {
+				self.modifiers.observe(e);
+			}
+		}
+	}
+
+	/// Let go of every key that is down on our device, straight away, skipping the queue.
+	fn release_all(&mut self) {
+		let mut events = self.set_device_shift(HashSet::new());
+		let tier_modifiers: HashSet<u16> =
+			self.extras.drain().flat_map(|(_, press)| press.modifiers).collect();
+		let held: HashSet<u16> = self.held.drain().map(|(_, code)| code).collect();
+		for code in held.into_iter().chain(self.passthrough_held.drain()).chain(tier_modifiers) {
+			if let Ok(key) = input_linux::Key::from_code(code) {
+				events.extend(Self::key_events(key, input_linux::KeyState::RELEASED));
+			}
+		}
+		self.overridden.clear();
+		self.queue.clear();
+		if !events.is_empty() {
+			if let Err(e) = self.handle.write(&events) {
+				log::error!("couldn't release held keys: {e}");
+			}
+		}
+	}
+
+	/// The Shift presses and releases that take our device to exactly `target` being down.
+	fn set_device_shift(&mut self, target: HashSet<u16>) -> Vec<input_linux::sys::input_event> {
+		let mut events = vec![];
+		for code in self.device_shift.difference(&target) {
+			events.extend(Self::key_events(
+				input_linux::Key::from_code(*code).unwrap(),
+				input_linux::KeyState::RELEASED,
+			));
+		}
+		for code in target.difference(&self.device_shift) {
+			events.extend(Self::key_events(
+				input_linux::Key::from_code(*code).unwrap(),
+				input_linux::KeyState::PRESSED,
+			));
+		}
+		self.device_shift = target;
+		events
+	}
+
+	/// Let go of `code` if another keyboard is holding it, so that pressing it again types again.
+	fn release_for_another_press(&mut self, code: u16, since: Instant) {
+		if !self.held.iter().any(|(_, held)| *held == code) {
+			return;
+		}
+		let release = Self::keys_events(&[code], input_linux::KeyState::RELEASED);
+		self.schedule(Duration::ZERO, since, release);
+	}
+
+	/// `key_events` for each of `codes` in turn.
+	fn keys_events(codes: &[u16], state: input_linux::KeyState) -> Vec<input_linux::sys::input_event> {
+		codes
+			.iter()
+			.filter_map(|code| input_linux::Key::from_code(*code).ok())
+			.flat_map(|key| Self::key_events(key, state))
+			.collect()
+	}
+
+	/// Tap each of `keys`, with Shift set as given whatever Caps Lock says, then hand Shift back
+	/// to the user.
+	fn type_keys(&mut self, keys: &[(u16, bool)], since: Instant) {
+		if keys.is_empty() {
+			return;
+		}
+		let delay = Duration::from_millis(self.config.key_delay_ms);
+		for &(code, shifted) in keys {
+			let Ok(key) = input_linux::Key::from_code(code) else {
+				continue;
+			};
+			let caps_locked = self.modifiers.caps_lock && self.config.layout.is_letter(code);
+			let target = if shifted ^ caps_locked {
+				HashSet::from([u16::from(input_linux::Key::LeftShift)])
+			} else {
+				HashSet::new()
+			};
+			let shift_events = self.set_device_shift(target);
+			let key_delay = if shift_events.is_empty() { Duration::ZERO } else { delay };
+			self.schedule(Duration::ZERO, since, shift_events);
+			let mut tap = Self::key_events(key, input_linux::KeyState::PRESSED);
+			tap.extend(Self::key_events(key, input_linux::KeyState::RELEASED));
+			self.schedule(key_delay, since, tap);
+		}
+		// as in send_key, any held key loses its override
+		self.overridden.clear();
+		let shift_events = self.set_device_shift(self.modifiers.shift.clone());
+		self.schedule(delay, since, shift_events);
+	}
+}
 
-		return OutputHid { handle, epoch };
+impl OutputSink for OutputHid {
+	fn next_due(&self) -> Option<Instant> {
+		self.queue.front().map(|s| s.due)
 	}
-	pub fn send_key(&mut self, k: &KeyEvent) {
+
+	fn flush(&mut self) {
+		let now = Instant::now();
+		while self.queue.front().is_some_and(|s| s.due <= now) {
+			let mut scheduled = self.queue.pop_front().unwrap();
+
+			let t = now.duration_since(self.epoch);
+			for e in scheduled.events.iter_mut() {
+				e.time = libc::timeval {
+					tv_sec: t.as_secs().try_into().unwrap(),
+					tv_usec: t.subsec_micros().into(),
+				};
+			}
+			if let Err(e) = self.handle.write(&scheduled.events) {
+				log::error!("couldn't inject {} events: {e}", scheduled.events.len());
+				continue;
+			}
+
+			let latency = now.saturating_duration_since(scheduled.since);
+			log::debug!("injected {} events {latency:?} after their input", scheduled.events.len());
+			self.latency.add(latency);
+		}
+	}
+
+	/// Press `k` with Shift set so it comes out in the case asked for by `config.shout_mode`,
+	/// whatever the user is holding and whatever Caps Lock says. Keys that don't shout on
+	/// `config.layout` come out as the user's own modifiers would have them. If `k` reaches one of
+	/// `config.tiers`, its modifiers are held along with the key. The key stays down until
+	/// `send_key_release`, so it autorepeats like any other held key.
+	fn send_key(&mut self, k: &KeyEvent) {
+		let delay = Duration::from_millis(self.config.key_delay_ms);
 		let code = k.scancode;
 		let velocity = k.velocity;
-		let mut t = std::time::Instant::now().duration_since(self.epoch);
-		let get_time = |t: std::time::Duration| {
-			input_linux::EventTime::new(t.as_secs().try_into().unwrap(), t.subsec_millis().try_into().unwrap())
-		};
 		let Ok(key) = input_linux::Key::from_code(code) else {
 			log::warn!("ignoring bad code {code}");
 			return;
 		};
-		let code = Into::<u16>::into(key);
 
 		log::info!("diff for {key:?}/{code:?} is {velocity}");
 
-		if k.caps {
-			self.handle
-				.write(&[*input_linux::InputEvent::from(input_linux::KeyEvent::new(
-					get_time(t),
-					input_linux::Key::LeftShift,
-					input_linux::KeyState::PRESSED,
-				))
-				.as_raw()])
-				.unwrap();
-
-			self.handle
-				.write(&[
-					*input_linux::InputEvent::from(input_linux::SynchronizeEvent::new(
-						get_time(t),
-						input_linux::SynchronizeKind::Report,
-						0,
-					))
-					.as_raw(),
-				])
-				.unwrap();
-
-			std::thread::sleep(Duration::from_millis(5));
-			t = t + Duration::from_millis(5);
-		}
-
-		self.handle
-			.write(&[*input_linux::InputEvent {
-				time: get_time(t),
-				kind: input_linux::EventKind::Key,
-				code: code,
-				value: 1,
+		if self.held.contains(&(k.device, code)) {
+			log::warn!("{key:?} pressed again without being released, ignoring");
+			return;
+		}
+		self.release_for_another_press(code, k.ts);
+
+		self.read_leds();
+
+		let layout = self.config.layout;
+		let tier = self.config.tier(k).map(|tier| {
+			log::debug!("{key:?} at {velocity} reached the tier from {}", tier.velocity);
+			Extras {
+				// validated with the rest of the config
+				modifiers: tier.modifier_scancodes().unwrap_or_default(),
 			}
-			.as_raw()])
-			.unwrap();
-
-		self.handle
-			.write(&[
-				*input_linux::InputEvent::from(input_linux::SynchronizeEvent::new(
-					get_time(t),
-					input_linux::SynchronizeKind::Report,
-					0,
-				))
-				.as_raw(),
-			])
-			.unwrap();
-
-		std::thread::sleep(Duration::from_millis(5));
-		t = t + Duration::from_millis(5);
-
-		self.handle
-			.write(&[*input_linux::InputEvent {
-				time: get_time(t),
-				kind: input_linux::EventKind::Key,
-				code: code,
-				value: 0,
+		});
+		let upper = self.config.upper(k, self.modifiers.upper(layout, code));
+		let caps_locked = self.modifiers.caps_lock && layout.is_letter(code);
+		let shift = upper ^ caps_locked;
+
+		let user_shift = !self.modifiers.shift.is_empty();
+		let target = if shift == user_shift {
+			self.modifiers.shift.clone()
+		} else if shift {
+			HashSet::from([u16::from(input_linux::Key::LeftShift)])
+		} else {
+			HashSet::new()
+		};
+		let shift_events = self.set_device_shift(target);
+		let key_delay = if shift_events.is_empty() {
+			Duration::ZERO
+		} else {
+			// any other held key loses its override, we only have the one Shift
+			self.overridden.clear();
+			delay
+		};
+		if shift != user_shift {
+			self.overridden.insert((k.device, code));
+		}
+
+		self.schedule(Duration::ZERO, k.ts, shift_events);
+		if let Some(press) = tier {
+			let modifier_events = Self::keys_events(&press.modifiers, input_linux::KeyState::PRESSED);
+			self.schedule(Duration::ZERO, k.ts, modifier_events);
+			self.extras.insert((k.device, code), press);
+		}
+		self.schedule(key_delay, k.ts, Self::key_events(key, input_linux::KeyState::PRESSED));
+		self.held.insert((k.device, code));
+	}
+
+	/// Press `c` with its modifiers and the user's own Shift, leaving them down until
+	/// `send_key_release`.
+	fn send_chord(&mut self, c: &ChordEvent) {
+		let delay = Duration::from_millis(self.config.key_delay_ms);
+		let code = c.scancode;
+		let Ok(key) = input_linux::Key::from_code(code) else {
+			log::warn!("ignoring bad code {code}");
+			return;
+		};
+		if self.held.contains(&(c.device, code)) {
+			log::warn!("{key:?} pressed again without being released, ignoring");
+			return;
+		}
+		log::info!("{key:?} held deep, sending it with {:?}", c.modifiers);
+		self.release_for_another_press(code, c.ts);
+
+		let shift_events = self.set_device_shift(self.modifiers.shift.clone());
+		if !shift_events.is_empty() {
+			self.overridden.clear();
+		}
+		self.schedule(Duration::ZERO, c.ts, shift_events);
+		let modifier_events = Self::keys_events(&c.modifiers, input_linux::KeyState::PRESSED);
+		self.schedule(Duration::ZERO, c.ts, modifier_events);
+		self.schedule(delay, c.ts, Self::key_events(key, input_linux::KeyState::PRESSED));
+		self.held.insert((c.device, code));
+		self.extras.insert(
+			(c.device, code),
+			Extras {
+				modifiers: c.modifiers.clone(),
+			},
+		);
+	}
+
+	fn send_key_release(&mut self, device: DeviceID, code: u16, ts: Instant) {
+		let delay = Duration::from_millis(self.config.key_delay_ms);
+		let Ok(key) = input_linux::Key::from_code(code) else {
+			log::warn!("ignoring bad code {code}");
+			return;
+		};
+		if !self.held.remove(&(device, code)) {
+			return;
+		}
+
+		// another keyboard holding the same key keeps it down
+		if !self.held.iter().any(|(_, held)| *held == code) {
+			let release = Self::key_events(key, input_linux::KeyState::RELEASED);
+			self.schedule(Duration::ZERO, ts, release);
+		}
+
+		if let Some(press) = self.extras.remove(&(device, code)) {
+			// another held key may have reached a tier with the same modifier
+			let releasing: Vec<u16> = press
+				.modifiers
+				.iter()
+				.copied()
+				.filter(|m| !self.extras.values().any(|other| other.modifiers.contains(m)))
+				.collect();
+			let modifier_events = Self::keys_events(&releasing, input_linux::KeyState::RELEASED);
+			self.schedule(Duration::ZERO, ts, modifier_events);
+		}
+
+		if self.overridden.remove(&(device, code)) && self.overridden.is_empty() {
+			let shift_events = self.set_device_shift(self.modifiers.shift.clone());
+			self.schedule(delay, ts, shift_events);
+		}
+	}
+
+	fn send_passthrough(&mut self, evs: &[input_linux::sys::input_event], ts: Instant) {
+		let mut user_shifted = false;
+		for e in evs {
+			self.modifiers.observe(e);
+			if e.type_ == input_linux::sys::EV_KEY as u16 && e.value != KEY_REPEAT {
+				if e.value == 0 {
+					self.passthrough_held.remove(&e.code);
+				} else {
+					self.passthrough_held.insert(e.code);
+				}
+			}
+			if e.type_ == input_linux::sys::EV_KEY as u16
+				&& e.value != KEY_REPEAT
+				&& SHIFT_KEYS.iter().any(|k| u16::from(*k) == e.code)
+			{
+				user_shifted = true;
+				if e.value == 0 {
+					self.device_shift.remove(&e.code);
+				} else {
+					self.device_shift.insert(e.code);
+				}
 			}
-			.as_raw()])
-			.unwrap();
-
-		self.handle
-			.write(&[
-				*input_linux::InputEvent::from(input_linux::SynchronizeEvent::new(
-					get_time(t),
-					input_linux::SynchronizeKind::Report,
-					0,
-				))
-				.as_raw(),
-			])
-			.unwrap();
-
-		if k.caps {
-			std::thread::sleep(Duration::from_millis(5));
-			t = t + Duration::from_millis(5);
-
-			self.handle
-				.write(&[*input_linux::InputEvent::from(input_linux::KeyEvent::new(
-					get_time(t),
-					input_linux::Key::LeftShift,
-					input_linux::KeyState::RELEASED,
-				))
-				.as_raw()])
-				.unwrap();
-
-			self.handle
-				.write(&[
-					*input_linux::InputEvent::from(input_linux::SynchronizeEvent::new(
-						get_time(t),
-						input_linux::SynchronizeKind::Report,
-						0,
-					))
-					.as_raw(),
-				])
-				.unwrap();
-		}
-	}
-
-	pub fn send_passthrough(&self, evs: &[input_linux::sys::input_event]) {
-		self.handle.write(evs).unwrap();
+		}
+
+		// our own device autorepeats, so the keyboard's repeats would double up
+		let evs: Vec<_> = evs
+			.iter()
+			.filter(|e| {
+				!(e.type_ == input_linux::sys::EV_KEY as u16 && e.value == KEY_REPEAT)
+			})
+			.copied()
+			.collect();
+		self.schedule(Duration::ZERO, ts, evs);
+
+		if user_shifted && !self.overridden.is_empty() {
+			// the user's own Shift wins over ours
+			self.overridden.clear();
+			let shift_events = self.set_device_shift(self.modifiers.shift.clone());
+			self.schedule(Duration::ZERO, ts, shift_events);
+		}
+	}
+
+	fn send_text(&mut self, text: &str, ts: Instant) {
+		let layout = self.config.layout;
+		// validated with the rest of the config if it came from a tier
+		let keys: Vec<(u16, bool)> = text.chars().filter_map(|c| layout.key(c)).collect();
+		self.type_keys(&keys, ts);
+	}
+
+	fn reconfigure(&mut self, config: OutputConfig) {
+		self.config = config;
+	}
+
+	fn releaser(&self) -> Option<Box<dyn Fn() + Send>> {
+		let handle = match self.handle.as_inner().try_clone() {
+			Ok(file) => uinput::UInputHandle::new(file),
+			Err(e) => {
+				log::error!("couldn't share the uinput device to release keys from: {e}");
+				return None;
+			}
+		};
+		Some(Box::new(move || {
+			// the kernel ignores releases of keys that aren't down
+			let codes: Vec<u16> = (0..248).collect();
+			let events = Self::keys_events(&codes, input_linux::KeyState::RELEASED);
+			if let Err(e) = handle.write(&events) {
+				log::error!("couldn't release held keys: {e}");
+			}
+		}))
+	}
+}
+
+impl Drop for OutputHid {
+	/// Leave nothing pressed and take the device away, even when unwinding from a panic.
+	fn drop(&mut self) {
+		self.release_all();
+		if let Err(e) = self.handle.dev_destroy() {
+			log::error!("couldn't destroy the uinput device: {e}");
+		}
 	}
 }
@@ -2,6 +2,7 @@
 use sqlite;
 
 use crate::hid::AnalogueReading;
+use crate::keycode;
 
 pub fn sqlite_connection() -> Result<sqlite::Connection, anyhow::Error> {
     let c = sqlite::open("recordings.sqlite")?;
@@ -71,3 +72,97 @@ impl <'a> Recorder<'a> {
         self.stmt.reset().unwrap();
     }
 }
+
+/// One row of the `events` table.
+pub struct RecordedEvent {
+    pub ts: f64,
+    pub key: String,
+    pub value: f32,
+}
+
+/// Every recorded event with `from <= ts <= to`, in order. Timestamps are unix seconds.
+pub fn events_between(c: &sqlite::Connection, from: f64, to: f64) -> Result<Vec<RecordedEvent>, anyhow::Error> {
+    let mut stmt = c.prepare("--sql
+        select ts, char, value
+        from events
+        where ts >= ? and ts <= ?
+        order by ts, rowid
+    ")?;
+    stmt.bind((1, from))?;
+    stmt.bind((2, to))?;
+    read_events(stmt)
+}
+
+/// A recording session: everything recorded by one run of the program.
+pub struct Session {
+    pub epoch: i64,
+    pub events: i64,
+    pub duration_secs: f64,
+}
+
+pub fn sessions(c: &sqlite::Connection) -> Result<Vec<Session>, anyhow::Error> {
+    let mut stmt = c.prepare("--sql
+        select session_epoch, count(*) as events, max(ts_secs_rel) as duration
+        from events
+        group by session_epoch
+        order by session_epoch
+    ")?;
+
+    let mut sessions = vec![];
+    while let sqlite::State::Row = stmt.next()? {
+        sessions.push(Session {
+            epoch: stmt.read::<i64, _>("session_epoch")?,
+            events: stmt.read::<i64, _>("events")?,
+            duration_secs: stmt.read::<f64, _>("duration")?,
+        });
+    }
+    Ok(sessions)
+}
+
+/// Every event recorded in the session starting at `epoch`, in order.
+pub fn session_events(c: &sqlite::Connection, epoch: i64) -> Result<Vec<RecordedEvent>, anyhow::Error> {
+    let mut stmt = c.prepare("--sql
+        select ts, char, value
+        from events
+        where session_epoch = ?
+        order by ts, rowid
+    ")?;
+    stmt.bind((1, epoch))?;
+    read_events(stmt)
+}
+
+fn read_events(mut stmt: sqlite::Statement) -> Result<Vec<RecordedEvent>, anyhow::Error> {
+    let mut events = vec![];
+    while let sqlite::State::Row = stmt.next()? {
+        events.push(RecordedEvent {
+            ts: stmt.read::<f64, _>("ts")?,
+            key: stmt.read::<String, _>("char")?,
+            value: stmt.read::<f64, _>("value")? as f32,
+        });
+    }
+    Ok(events)
+}
+
+/// Group recorded events back into the reports they were read in, as if they had just been read
+/// starting at `start`. Events that were read together share a timestamp.
+pub fn to_reports(events: &[RecordedEvent], start: std::time::Instant) -> Vec<(std::time::Instant, Vec<AnalogueReading>)> {
+    let Some(first) = events.first() else {
+        return vec![];
+    };
+
+    let mut reports: Vec<(std::time::Instant, Vec<AnalogueReading>)> = vec![];
+    let mut last_ts = None;
+    for e in events {
+        let Some(scancode) = keycode::scancode_from_name(&e.key) else {
+            log::warn!("ignoring recorded event for unknown key {:?}", e.key);
+            continue;
+        };
+        let ts = start + std::time::Duration::from_secs_f64((e.ts - first.ts).max(0.0));
+        if last_ts != Some(e.ts) {
+            reports.push((ts, vec![]));
+            last_ts = Some(e.ts);
+        }
+        reports.last_mut().unwrap().1.push(AnalogueReading { scancode, value: e.value, ts });
+    }
+    reports
+}
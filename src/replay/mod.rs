@@ -0,0 +1,122 @@
+use std::io::Write;
+
+use anyhow::{bail, Context};
+use chrono::TimeZone;
+
+use crate::{calibration, config, recorder, watcher};
+
+/// Name for `--against` that means no profile at all, just the `[watcher]` config
+const NO_PROFILE: &str = "none";
+
+#[derive(clap::Args)]
+pub struct ReplayArgs {
+	/// Session to replay, as listed by --list. Defaults to the latest
+	#[arg(long)]
+	session: Option<i64>,
+	/// List the recorded sessions instead of replaying one
+	#[arg(long)]
+	list: bool,
+	/// Feed readings in with their original timing rather than as fast as possible
+	#[arg(long)]
+	realtime: bool,
+	/// Also replay with this calibration profile, or `none`, and show where the two differ
+	#[arg(long)]
+	against: Option<String>,
+}
+
+/// Feed a recorded session back through the watcher and print what it would have typed.
+///
+/// Only the analogue keys are recorded, so the text has no spaces or other passthrough keys,
+/// and nothing is known about Shift or Caps Lock: upper case means the watcher shouted.
+pub fn run(config: &config::Config, args: &ReplayArgs) -> anyhow::Result<()> {
+	let con = recorder::sqlite_connection()?;
+	let sessions = recorder::sessions(&con)?;
+
+	if args.list {
+		for s in sessions.iter() {
+			let started = match chrono::Local.timestamp_opt(s.epoch, 0).single() {
+				Some(t) => t.format("%Y-%m-%d %H:%M:%S").to_string(),
+				None => "?".to_string(),
+			};
+			println!(
+				"{}  {started}  {} events over {:.1}s",
+				s.epoch, s.events, s.duration_secs
+			);
+		}
+		return Ok(());
+	}
+
+	let epoch = match args.session {
+		Some(epoch) => epoch,
+		None => sessions.last().context("no sessions recorded yet")?.epoch,
+	};
+	let events = recorder::session_events(&con, epoch)?;
+	if events.is_empty() {
+		bail!("no events recorded for session {epoch}");
+	}
+	eprintln!("replaying session {epoch}, {} events", events.len());
+
+	let text = shouted_text(&config.calibration, &config.output, &events, args.realtime);
+
+	let Some(against) = &args.against else {
+		return Ok(());
+	};
+	let other = if against == NO_PROFILE {
+		calibration::Calibration::uniform(config.watcher)
+	} else {
+		calibration::Profile::load(against)?.resolve(against, config.watcher)?
+	};
+	let other_text = shouted_text(&other, &config.output, &events, false);
+
+	let label = config.profile.as_deref().unwrap_or(NO_PROFILE);
+	print_diff((label, &text), (against, &other_text));
+	Ok(())
+}
+
+/// Replay `events`, printing each character as the watcher fires it on `output.layout`, and
+/// return the whole text. Tiers decide the case, but their `append` text is left out.
+fn shouted_text(
+	calibration: &calibration::Calibration,
+	output: &config::OutputConfig,
+	events: &[recorder::RecordedEvent],
+	realtime: bool,
+) -> String {
+	let reports = recorder::to_reports(events, std::time::Instant::now());
+	let mut text = String::new();
+	let mut stdout = std::io::stdout();
+	watcher::run_offline(calibration, reports, realtime, |k| {
+		if let Some(c) = output.layout.char(k.scancode, output.shouts(&k)) {
+			text.push(c);
+			if realtime {
+				print!("{c}");
+				let _ = stdout.flush();
+			}
+		}
+	});
+	if !realtime {
+		print!("{text}");
+	}
+	println!();
+	text
+}
+
+/// Print both texts one above the other, marking the characters that differ.
+fn print_diff((label, text): (&str, &str), (other_label, other_text): (&str, &str)) {
+	let width = label.len().max(other_label.len());
+	let a: Vec<char> = text.chars().collect();
+	let b: Vec<char> = other_text.chars().collect();
+	let marks: String = (0..a.len().max(b.len()))
+		.map(|i| if a.get(i) == b.get(i) { ' ' } else { '^' })
+		.collect();
+
+	println!();
+	println!("{label:>width$}: {text}");
+	println!("{other_label:>width$}: {other_text}");
+	println!("{:>width$}  {}", "", marks.trim_end());
+
+	let differing = marks.chars().filter(|c| *c == '^').count();
+	println!("{differing} of {} characters differ", a.len().max(b.len()));
+	if a.len() != b.len() {
+		println!("({label} fired {} presses, {other_label} fired {})", a.len(), b.len());
+	}
+}
@@ -0,0 +1,427 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::SyncSender;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context};
+use log::{info, warn};
+use wooting_analog_plugin_dev::wooting_analog_common::DeviceID;
+
+use crate::layout::Layout;
+use crate::{hid, keycode, recorder, watcher};
+
+/// How often the simulated keyboard sends an analogue report, like the 1kHz a real one polls at
+const REPORT_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Time for a soft press of the synthetic typist to reach the bottom, slow enough not to shout
+/// with the default cutoff
+const SOFT_PRESS: Duration = Duration::from_millis(25);
+/// Time for a hard press to reach the bottom, fast enough to shout with the default cutoff
+const HARD_PRESS: Duration = Duration::from_millis(3);
+const HOLD: Duration = Duration::from_millis(30);
+const RELEASE: Duration = Duration::from_millis(10);
+/// Gap between letting go of one key and starting the next
+const KEY_GAP: Duration = Duration::from_millis(40);
+
+/// Device id the simulated keyboard reports as, `0x0` under `[devices]` in the config.
+/// Real keyboards get a hash of their serial number, which won't be zero in practice
+pub const DEVICE_ID: DeviceID = 0;
+
+/// Where a `SimulatedDevice` gets its key movements from, as given to `--simulate`.
+///
+/// - `text:<text>` types `<text>` with a synthetic typing model, hitting upper case letters hard
+/// - `session:<epoch>` replays a session from the recordings database, or `session:latest`
+/// - `script:<path>` plays a script of `<ms> <key> <value>` lines, see `parse_script`
+#[derive(Debug, Clone)]
+pub enum Source {
+	Text(String),
+	Session(Option<i64>),
+	Script(PathBuf),
+}
+
+impl FromStr for Source {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let Some((kind, arg)) = s.split_once(':') else {
+			return Err("expected text:<text>, session:<epoch|latest> or script:<path>".to_string());
+		};
+		match kind {
+			"text" => Ok(Source::Text(arg.to_string())),
+			"session" if arg == "latest" => Ok(Source::Session(None)),
+			"session" => arg
+				.parse()
+				.map(|epoch| Source::Session(Some(epoch)))
+				.map_err(|e| format!("bad session epoch {arg:?}: {e}")),
+			"script" => Ok(Source::Script(PathBuf::from(arg))),
+			_ => Err(format!("unknown simulation source {kind:?}")),
+		}
+	}
+}
+
+/// One key moving to an analogue `value` from 0.0 to 1.0 at `at` from the start of the
+/// simulation. Keys that aren't shoutable are passed through, pressed while `value` is above 0.
+#[derive(Debug, Clone, Copy)]
+pub struct Movement {
+	pub at: Duration,
+	pub scancode: u16,
+	pub value: f32,
+}
+
+/// A keyboard that doesn't exist, sending `hid::Input`s the way `hid::WootingPlugin` would.
+///
+/// Once every movement has been played it sends `hid::Input::Fin`, so the pipeline shuts down.
+pub struct SimulatedDevice {
+	movements: Vec<Movement>,
+	stop: Arc<AtomicBool>,
+	worker: Option<JoinHandle<()>>,
+}
+
+impl SimulatedDevice {
+	pub fn new(mut movements: Vec<Movement>) -> Self {
+		movements.sort_by_key(|m| m.at);
+		SimulatedDevice {
+			movements,
+			stop: Arc::new(AtomicBool::new(false)),
+			worker: None,
+		}
+	}
+
+	pub fn from_source(source: &Source) -> anyhow::Result<Self> {
+		let movements = match source {
+			Source::Text(text) => typing_model(text),
+			Source::Session(epoch) => session_movements(*epoch)?,
+			Source::Script(path) => {
+				let text = std::fs::read_to_string(path)
+					.with_context(|| format!("couldn't read simulation script {path:?}"))?;
+				parse_script(&text).with_context(|| format!("invalid simulation script {path:?}"))?
+			}
+		};
+		Ok(Self::new(movements))
+	}
+}
+
+impl hid::InputSource for SimulatedDevice {
+	fn start(&mut self, tx: SyncSender<hid::Input>, routing: hid::Routing) -> anyhow::Result<()> {
+		let movements = std::mem::take(&mut self.movements);
+		let stop = Arc::clone(&self.stop);
+		info!("starting simulated keyboard, {} key movements", movements.len());
+
+		self.worker = Some(thread::spawn(move || {
+			let start = Instant::now();
+			let mut analogue: BTreeMap<u16, f32> = BTreeMap::new();
+			let mut passed_held: HashSet<u16> = HashSet::new();
+			let mut movements = movements.into_iter().peekable();
+			let mut next_report = Duration::ZERO;
+
+			while movements.peek().is_some() || !analogue.is_empty() {
+				if stop.load(Ordering::Relaxed) {
+					return;
+				}
+				thread::sleep((start + next_report).saturating_duration_since(Instant::now()));
+
+				let mut passthrough = vec![];
+				while let Some(m) = movements.next_if(|m| m.at <= next_report) {
+					if routing.shoutable.contains(m.scancode) {
+						if m.value > 0.0 {
+							analogue.insert(m.scancode, m.value.min(1.0));
+						} else {
+							analogue.remove(&m.scancode);
+						}
+					} else if m.value > 0.0 {
+						if passed_held.insert(m.scancode) {
+							passthrough.extend(key_events(m.scancode, true));
+						}
+					} else if passed_held.remove(&m.scancode) {
+						passthrough.extend(key_events(m.scancode, false));
+					}
+				}
+
+				// as if read on time, so a busy machine doesn't change how fast keys went down
+				let ts = start + next_report;
+				let readings = analogue
+					.iter()
+					.map(|(&scancode, &value)| hid::AnalogueReading { scancode, value, ts })
+					.collect();
+				if tx.send(hid::Input::Analogue(DEVICE_ID, readings)).is_err() {
+					return;
+				}
+				if !passthrough.is_empty()
+					&& tx.send(hid::Input::PassThrough(DEVICE_ID, passthrough, ts)).is_err()
+				{
+					return;
+				}
+				next_report += REPORT_INTERVAL;
+			}
+
+			info!("simulated keyboard has finished");
+			let _ = tx.send(hid::Input::Fin());
+		}));
+		Ok(())
+	}
+
+	fn stop(&mut self) {
+		self.stop.store(true, Ordering::Relaxed);
+		if let Some(worker) = self.worker.take() {
+			if worker.join().is_err() {
+				warn!("simulated keyboard thread panicked");
+			}
+		}
+	}
+}
+
+/// An `EV_KEY` event for `scancode` and the report that goes with it, as read from evdev.
+fn key_events(scancode: u16, pressed: bool) -> Vec<input_linux::sys::input_event> {
+	let time = libc::timeval {
+		tv_sec: 0,
+		tv_usec: 0,
+	};
+	vec![
+		input_linux::sys::input_event {
+			time,
+			type_: input_linux::sys::EV_KEY as u16,
+			code: scancode,
+			value: pressed.into(),
+		},
+		input_linux::sys::input_event {
+			time,
+			type_: input_linux::sys::EV_SYN as u16,
+			code: input_linux::sys::SYN_REPORT as u16,
+			value: 0,
+		},
+	]
+}
+
+/// Movements for typing `text` on a US layout, one key at a time. Upper case letters are typed
+/// as hard presses of the lower case key, everything else softly, holding Shift where needed.
+///
+/// Every key is pressed the same analogue way, in case it is shoutable. Keys that turn out not
+/// to be are passed through as a press and a release.
+pub fn typing_model(text: &str) -> Vec<Movement> {
+	let shift = u16::from(input_linux::Key::LeftShift);
+	let mut movements = vec![];
+	let mut at = Duration::ZERO;
+	let mut tap = |at: &mut Duration, scancode: u16, value: f32| {
+		movements.push(Movement {
+			at: *at,
+			scancode,
+			value,
+		});
+	};
+
+	for c in text.chars() {
+		let hard = c.is_ascii_uppercase();
+		let Some((scancode, shifted)) = Layout::Us.key(if hard { c.to_ascii_lowercase() } else { c })
+		else {
+			warn!("can't type {c:?}, skipping it");
+			continue;
+		};
+
+		if shifted {
+			tap(&mut at, shift, 1.0);
+			at += REPORT_INTERVAL;
+		}
+
+		let press = if hard { HARD_PRESS } else { SOFT_PRESS };
+		let steps = (press.as_millis() / REPORT_INTERVAL.as_millis()).max(1) as u32;
+		for step in 1..=steps {
+			tap(&mut at, scancode, step as f32 / steps as f32);
+			at += REPORT_INTERVAL;
+		}
+		at += HOLD;
+		let steps = (RELEASE.as_millis() / REPORT_INTERVAL.as_millis()).max(1) as u32;
+		for step in (0..steps).rev() {
+			tap(&mut at, scancode, step as f32 / steps as f32);
+			at += REPORT_INTERVAL;
+		}
+
+		if shifted {
+			at += REPORT_INTERVAL;
+			tap(&mut at, shift, 0.0);
+		}
+		at += KEY_GAP;
+	}
+	movements
+}
+
+/// Movements for a session from the recordings database, or the latest one if `epoch` is `None`.
+fn session_movements(epoch: Option<i64>) -> anyhow::Result<Vec<Movement>> {
+	let con = recorder::sqlite_connection()?;
+	let epoch = match epoch {
+		Some(epoch) => epoch,
+		None => recorder::sessions(&con)?
+			.last()
+			.context("no sessions recorded yet")?
+			.epoch,
+	};
+	let events = recorder::session_events(&con, epoch)?;
+	if events.is_empty() {
+		bail!("no events recorded for session {epoch}");
+	}
+	Ok(recorded_movements(&events))
+}
+
+/// Movements for recorded `events`, grouped back into the reports they were read in.
+///
+/// Older sessions don't record releases, and the recorder drops readings when it falls behind,
+/// so a key missing from a report is released there, as `watcher::ReleaseTracker` would, and
+/// anything still down at the end is released after the last report.
+pub fn recorded_movements(events: &[recorder::RecordedEvent]) -> Vec<Movement> {
+	let start = Instant::now();
+	let reports = recorder::to_reports(events, start);
+	let end = reports.last().map(|(ts, _)| (*ts + REPORT_INTERVAL, vec![]));
+	let mut releases = watcher::ReleaseTracker::default();
+	let mut movements = vec![];
+	for (ts, readings) in reports.into_iter().chain(end) {
+		for r in releases.complete(readings, ts) {
+			movements.push(Movement {
+				at: ts - start,
+				scancode: r.scancode,
+				value: r.value,
+			});
+		}
+	}
+	movements
+}
+
+/// Parse a simulation script: one `<ms> <key> <value>` movement per line, with `ms` counted from
+/// the start and keys named as in `input_linux::Key`. Blank lines and `#` comments are ignored.
+///
+/// ```text
+/// # a hard A, then a space
+/// 0    A      0.5
+/// 2    A      1.0
+/// 30   A      0
+/// 60   Space  1
+/// 90   Space  0
+/// ```
+pub fn parse_script(text: &str) -> anyhow::Result<Vec<Movement>> {
+	let mut movements = vec![];
+	for (i, line) in text.lines().enumerate() {
+		let line = line.split('#').next().unwrap_or_default().trim();
+		if line.is_empty() {
+			continue;
+		}
+		let fields: Vec<&str> = line.split_whitespace().collect();
+		let [ms, key, value] = fields[..] else {
+			bail!("line {}: expected `<ms> <key> <value>`, got {line:?}", i + 1);
+		};
+		let ms: u64 = ms
+			.parse()
+			.with_context(|| format!("line {}: bad time {ms:?}", i + 1))?;
+		let Some(scancode) = keycode::scancode_from_name(key) else {
+			bail!("line {}: unknown key {key:?}", i + 1);
+		};
+		let value: f32 = value
+			.parse()
+			.with_context(|| format!("line {}: bad value {value:?}", i + 1))?;
+		if !(0.0..=1.0).contains(&value) {
+			bail!("line {}: value must be between 0.0 and 1.0, got {value}", i + 1);
+		}
+		movements.push(Movement {
+			at: Duration::from_millis(ms),
+			scancode,
+			value,
+		});
+	}
+	Ok(movements)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn moves(movements: &[Movement]) -> Vec<(Duration, String, f32)> {
+		movements
+			.iter()
+			.map(|m| (m.at, keycode::key_name(m.scancode).unwrap(), m.value))
+			.collect()
+	}
+
+	fn ms(ms: u64) -> Duration {
+		Duration::from_millis(ms)
+	}
+
+	#[test]
+	fn releases_keys_missing_from_recorded_reports() {
+		let event = |ts: f64, key: &str, value: f32| recorder::RecordedEvent {
+			ts,
+			key: key.to_string(),
+			value,
+		};
+		let events = [
+			event(1.0, "A", 0.5),
+			event(1.0, "B", 0.3),
+			event(1.25, "A", 1.0),
+		];
+		assert_eq!(
+			moves(&recorded_movements(&events)),
+			[
+				(ms(0), "A".to_string(), 0.5),
+				(ms(0), "B".to_string(), 0.3),
+				(ms(250), "A".to_string(), 1.0),
+				(ms(250), "B".to_string(), 0.0),
+				(ms(251), "A".to_string(), 0.0),
+			]
+		);
+	}
+
+	#[test]
+	fn parses_scripts() {
+		let script = "
+			# a hard A
+			0  A     0.5
+			2  a     1.0 # names ignore case
+
+			30 Space 0
+		";
+		assert_eq!(
+			moves(&parse_script(script).unwrap()),
+			[
+				(ms(0), "A".to_string(), 0.5),
+				(ms(2), "A".to_string(), 1.0),
+				(ms(30), "Space".to_string(), 0.0),
+			]
+		);
+	}
+
+	#[test]
+	fn rejects_bad_script_lines() {
+		for line in ["0 A", "0 A 0.5 1", "x A 0.5", "0 NotAKey 0.5", "0 A high", "0 A 1.5"] {
+			let e = parse_script(&format!("\n{line}\n")).unwrap_err();
+			assert!(e.to_string().starts_with("line 2: "), "{line:?} gave {e}");
+		}
+	}
+
+	#[test]
+	fn parses_sources() {
+		assert!(matches!("text:Hi there".parse(), Ok(Source::Text(t)) if t == "Hi there"));
+		assert!(matches!("session:latest".parse(), Ok(Source::Session(None))));
+		assert!(matches!("session:1700000000".parse(), Ok(Source::Session(Some(1700000000)))));
+		assert!(matches!("script:a:b".parse(), Ok(Source::Script(p)) if p.as_os_str() == "a:b"));
+		assert!("session:soon".parse::<Source>().is_err());
+		assert!("text".parse::<Source>().is_err());
+		assert!("keyboard:1".parse::<Source>().is_err());
+	}
+
+	#[test]
+	fn types_upper_case_as_hard_presses() {
+		let movements = typing_model("aA");
+		let a = keycode::scancode_from_name("A").unwrap();
+		let shift = u16::from(input_linux::Key::LeftShift);
+		assert!(movements.iter().all(|m| m.scancode == a));
+		assert!(!movements.iter().any(|m| m.scancode == shift));
+		// how long each press takes to reach the bottom
+		let to_bottom = |press: &[Movement]| {
+			press.iter().find(|m| m.value == 1.0).unwrap().at - press[0].at
+		};
+		let released = movements.iter().position(|m| m.value == 0.0).unwrap();
+		let (soft, hard) = movements.split_at(released + 1);
+		assert_eq!(hard.last().unwrap().value, 0.0);
+		assert!(to_bottom(hard) < to_bottom(soft));
+	}
+}
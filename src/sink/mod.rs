@@ -0,0 +1,176 @@
+use std::io::Write;
+use std::path::Path;
+use std::time::Instant;
+
+use anyhow::Context;
+use wooting_analog_plugin_dev::wooting_analog_common::DeviceID;
+
+use crate::watcher::{ChordEvent, KeyEvent};
+use crate::{config::OutputConfig, hid, keycode, outputhid};
+
+/// Somewhere for the pipeline's output thread to send keys.
+///
+/// A sink may queue what it is given instead of acting on it straight away, in which case
+/// `next_due` says when the output thread should next call `flush`. Each `ts` is when the input
+/// behind it was read, for measuring latency.
+pub trait OutputSink: Send {
+	/// A press found by `watcher::KeyWatcher`, shouting if `k.caps`
+	fn send_key(&mut self, k: &KeyEvent);
+
+	/// The release of a key on `device` previously given to `send_key` or `send_chord`
+	fn send_key_release(&mut self, device: DeviceID, code: u16, ts: Instant);
+
+	/// A key held down deep, to press with its modifiers held
+	fn send_chord(&mut self, c: &ChordEvent);
+
+	/// Events from the keyboard that don't go through the watcher
+	fn send_passthrough(&mut self, evs: &[input_linux::sys::input_event], ts: Instant);
+
+	/// Text to type as it comes out on `config.layout`, like a tier's `append`
+	fn send_text(&mut self, text: &str, ts: Instant);
+
+	/// How far down a key mapped in `config.midi` is, whenever `device` reports it
+	fn send_pressure(&mut self, _device: DeviceID, _reading: &hid::AnalogueReading) {}
+
+	fn reconfigure(&mut self, config: OutputConfig);
+
+	/// Something that lets go of every key this sink holds down from another thread without
+	/// waiting on the pipeline, for when it has panicked or stopped responding.
+	fn releaser(&self) -> Option<Box<dyn Fn() + Send>> {
+		None
+	}
+
+	/// When `flush` next has something to do, if anything is queued
+	fn next_due(&self) -> Option<Instant> {
+		None
+	}
+
+	/// Act on everything queued that is due
+	fn flush(&mut self) {}
+}
+
+/// Where `--output-to` should send text rather than a file.
+pub const STDOUT: &str = "-";
+
+/// Writes the characters that would have been typed, as they would have come out on
+/// `config.layout`.
+///
+/// Only printable keys, Space, Tab and Enter are written, and Backspace as `\b`. Everything else
+/// is dropped, including chords and the modifiers of `config.tiers`, though the tiers' `append`
+/// text is written.
+pub struct TextSink {
+	out: Box<dyn Write + Send>,
+	config: OutputConfig,
+	modifiers: outputhid::Modifiers,
+}
+
+impl TextSink {
+	pub fn new(out: Box<dyn Write + Send>, config: OutputConfig) -> Self {
+		TextSink {
+			out,
+			config,
+			modifiers: outputhid::Modifiers::default(),
+		}
+	}
+
+	/// A sink writing to stdout if `path` is `STDOUT`, or else appending to the file at `path`.
+	pub fn open(path: &Path, config: OutputConfig) -> anyhow::Result<Self> {
+		let out: Box<dyn Write + Send> = if path.as_os_str() == STDOUT {
+			Box::new(std::io::stdout())
+		} else {
+			Box::new(
+				std::fs::OpenOptions::new()
+					.create(true)
+					.append(true)
+					.open(path)
+					.with_context(|| format!("couldn't open {path:?} for output"))?,
+			)
+		};
+		Ok(Self::new(out, config))
+	}
+
+	fn write_char(&mut self, c: char) {
+		let mut buf = [0; 4];
+		let res = self
+			.out
+			.write_all(c.encode_utf8(&mut buf).as_bytes())
+			.and_then(|_| self.out.flush());
+		if let Err(e) = res {
+			log::error!("failed writing output text: {e}");
+		}
+	}
+}
+
+impl OutputSink for TextSink {
+	fn send_key(&mut self, k: &KeyEvent) {
+		let layout = self.config.layout;
+		let natural = self.modifiers.upper(layout, k.scancode);
+		if let Some(c) = layout.char(k.scancode, self.config.upper(k, natural)) {
+			self.write_char(c);
+		}
+	}
+
+	fn send_key_release(&mut self, _device: DeviceID, _code: u16, _ts: Instant) {}
+
+	fn send_chord(&mut self, c: &ChordEvent) {
+		let names: Vec<String> = c
+			.modifiers
+			.iter()
+			.chain([&c.scancode])
+			.filter_map(|code| keycode::key_name(*code))
+			.collect();
+		log::info!("not writing chord {}", names.join("+"));
+	}
+
+	fn send_passthrough(&mut self, evs: &[input_linux::sys::input_event], _ts: Instant) {
+		for e in evs {
+			self.modifiers.observe(e);
+			if e.type_ != input_linux::sys::EV_KEY as u16 || e.value == 0 {
+				continue;
+			}
+			if e.code == u16::from(input_linux::Key::Backspace) {
+				self.write_char('\u{8}');
+				continue;
+			}
+			let layout = self.config.layout;
+			if let Some(c) = layout.char(e.code, self.modifiers.upper(layout, e.code)) {
+				self.write_char(c);
+			}
+		}
+	}
+
+	fn send_text(&mut self, text: &str, _ts: Instant) {
+		text.chars().for_each(|c| self.write_char(c));
+	}
+
+	fn reconfigure(&mut self, config: OutputConfig) {
+		self.config = config;
+	}
+}
+
+#[cfg(test)]
+pub mod testing {
+	use std::io::Write;
+	use std::sync::{Arc, Mutex};
+
+	/// Somewhere for a `TextSink` to write that a test can read back.
+	#[derive(Clone, Default)]
+	pub struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+	impl Buffer {
+		/// Everything written so far.
+		pub fn text(&self) -> String {
+			String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+		}
+	}
+
+	impl Write for Buffer {
+		fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+			self.0.lock().unwrap().write(buf)
+		}
+
+		fn flush(&mut self) -> std::io::Result<()> {
+			Ok(())
+		}
+	}
+}
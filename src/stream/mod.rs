@@ -0,0 +1,355 @@
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::config::{StreamConfig, StreamFormat};
+use crate::{hid, keycode, watcher};
+
+/// How many messages can wait to be sent before new ones are dropped
+const QUEUE_LEN: usize = 4096;
+
+enum Message {
+	Reading(hid::AnalogueReading),
+	Key(watcher::KeyEvent),
+}
+
+/// A message as it goes out, with `ts` in seconds since the Unix epoch.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Event {
+	Reading {
+		scancode: u16,
+		key: Option<String>,
+		value: f32,
+		ts: f64,
+	},
+	Key {
+		scancode: u16,
+		key: Option<String>,
+		velocity: f32,
+		caps: bool,
+		ts: f64,
+	},
+}
+
+/// Publishes every analogue reading and every press the watcher fires to `config.address`
+/// over UDP, for visualisations and games to use.
+///
+/// The sending happens on its own thread behind a queue of `QUEUE_LEN` messages. If whatever is
+/// listening can't keep up, messages are dropped rather than holding up the pipeline.
+#[derive(Clone)]
+pub struct Stream {
+	tx: SyncSender<Message>,
+	enabled: Arc<AtomicBool>,
+	/// Whether we are dropping messages, so it is only logged once each time the queue fills
+	dropping: Arc<AtomicBool>,
+	/// A new config for the sending thread to pick up before its next message
+	reconfigured: Arc<Mutex<Option<StreamConfig>>>,
+}
+
+impl Stream {
+	/// Start the sending thread, which runs until every clone of the `Stream` is dropped.
+	pub fn spawn(config: &StreamConfig) -> (Self, JoinHandle<()>) {
+		let (tx, rx) = std::sync::mpsc::sync_channel(QUEUE_LEN);
+		let stream = Stream {
+			tx,
+			enabled: Arc::new(AtomicBool::new(config.address.is_some())),
+			dropping: Arc::new(AtomicBool::new(false)),
+			reconfigured: Arc::new(Mutex::new(None)),
+		};
+		let config = config.clone();
+		let reconfigured = Arc::clone(&stream.reconfigured);
+		let thread = thread::spawn(move || publish(config, rx, reconfigured));
+		(stream, thread)
+	}
+
+	pub fn reading(&self, reading: &hid::AnalogueReading) {
+		self.queue(Message::Reading(reading.clone()));
+	}
+
+	pub fn key(&self, k: &watcher::KeyEvent) {
+		self.queue(Message::Key(k.clone()));
+	}
+
+	pub fn reconfigure(&self, config: &StreamConfig) {
+		// not through the queue, which could be full and would hold up the input thread
+		*self.reconfigured.lock().unwrap() = Some(config.clone());
+		self.enabled.store(config.address.is_some(), Ordering::Relaxed);
+	}
+
+	fn queue(&self, message: Message) {
+		if !self.enabled.load(Ordering::Relaxed) {
+			return;
+		}
+		match self.tx.try_send(message) {
+			Ok(()) => self.dropping.store(false, Ordering::Relaxed),
+			Err(TrySendError::Full(_)) => {
+				if !self.dropping.swap(true, Ordering::Relaxed) {
+					log::warn!("stream can't keep up, dropping messages");
+				}
+			}
+			Err(TrySendError::Disconnected(_)) => {}
+		}
+	}
+}
+
+/// Send everything from `rx` until it closes, switching to whatever config is left in
+/// `reconfigured`.
+fn publish(
+	mut config: StreamConfig,
+	rx: Receiver<Message>,
+	reconfigured: Arc<Mutex<Option<StreamConfig>>>,
+) {
+	let mut target = connect(&config);
+	for message in rx {
+		if let Some(new) = reconfigured.lock().unwrap().take() {
+			config = new;
+			target = connect(&config);
+		}
+		let event = match message {
+			Message::Reading(r) => Event::Reading {
+				scancode: r.scancode,
+				key: keycode::key_name(r.scancode),
+				value: r.value,
+				ts: unix_time(r.ts),
+			},
+			Message::Key(k) => Event::Key {
+				scancode: k.scancode,
+				key: keycode::key_name(k.scancode),
+				velocity: k.velocity,
+				caps: k.caps,
+				ts: unix_time(k.ts),
+			},
+		};
+		let Some((socket, addr)) = &target else {
+			continue;
+		};
+		let datagram = match config.format {
+			StreamFormat::Osc => osc(&event),
+			StreamFormat::Json => serde_json::to_vec(&event).expect("events always serialise"),
+		};
+		if let Err(e) = socket.send_to(&datagram, addr) {
+			// most likely nothing listening yet, which is fine
+			log::debug!("couldn't send to {addr}: {e}");
+		}
+	}
+	log::info!("closing stream");
+}
+
+/// A socket to send to the configured address from, if there is one.
+fn connect(config: &StreamConfig) -> Option<(UdpSocket, SocketAddr)> {
+	// already validated
+	let addr = config.socket_addr().ok()??;
+	let local: SocketAddr = if addr.is_ipv4() {
+		([0, 0, 0, 0], 0).into()
+	} else {
+		([0u16; 8], 0).into()
+	};
+	match UdpSocket::bind(local) {
+		Ok(socket) => {
+			log::info!("streaming {:?} to {addr}", config.format);
+			Some((socket, addr))
+		}
+		Err(e) => {
+			log::error!("couldn't open a socket for the stream: {e}");
+			None
+		}
+	}
+}
+
+/// `ts` as seconds since the Unix epoch.
+fn unix_time(ts: Instant) -> f64 {
+	let time = SystemTime::now() - ts.elapsed();
+	time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64()
+}
+
+/// `event` as an OSC message, with the fields in the order they are declared and an empty name
+/// for keys without one.
+fn osc(event: &Event) -> Vec<u8> {
+	enum Arg<'a> {
+		Int(i32),
+		Float(f32),
+		Double(f64),
+		Str(&'a str),
+	}
+	let (address, args) = match event {
+		Event::Reading {
+			scancode,
+			key,
+			value,
+			ts,
+		} => (
+			"/wooting/reading",
+			vec![
+				Arg::Int(*scancode as i32),
+				Arg::Str(key.as_deref().unwrap_or_default()),
+				Arg::Float(*value),
+				Arg::Double(*ts),
+			],
+		),
+		Event::Key {
+			scancode,
+			key,
+			velocity,
+			caps,
+			ts,
+		} => (
+			"/wooting/key",
+			vec![
+				Arg::Int(*scancode as i32),
+				Arg::Str(key.as_deref().unwrap_or_default()),
+				Arg::Float(*velocity),
+				Arg::Int(*caps as i32),
+				Arg::Double(*ts),
+			],
+		),
+	};
+
+	// strings are null terminated and padded to a multiple of 4 bytes
+	fn push_str(buf: &mut Vec<u8>, s: &str) {
+		buf.extend_from_slice(s.as_bytes());
+		buf.resize((buf.len() / 4 + 1) * 4, 0);
+	}
+	let mut buf = vec![];
+	push_str(&mut buf, address);
+	let mut tags = ",".to_string();
+	for arg in args.iter() {
+		tags.push(match arg {
+			Arg::Int(_) => 'i',
+			Arg::Float(_) => 'f',
+			Arg::Double(_) => 'd',
+			Arg::Str(_) => 's',
+		});
+	}
+	push_str(&mut buf, &tags);
+	for arg in args {
+		match arg {
+			Arg::Int(i) => buf.extend_from_slice(&i.to_be_bytes()),
+			Arg::Float(f) => buf.extend_from_slice(&f.to_be_bytes()),
+			Arg::Double(d) => buf.extend_from_slice(&d.to_be_bytes()),
+			Arg::Str(s) => push_str(&mut buf, s),
+		}
+	}
+	buf
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use wooting_analog_plugin_dev::wooting_analog_common::DeviceID;
+
+	use super::*;
+
+	#[test]
+	fn encodes_osc_messages() {
+		let event = Event::Key {
+			scancode: 30,
+			key: Some("A".to_string()),
+			velocity: 1.5,
+			caps: true,
+			ts: 2.0,
+		};
+		let mut expected = b"/wooting/key\0\0\0\0,isfid\0\0".to_vec();
+		expected.extend_from_slice(&30i32.to_be_bytes());
+		expected.extend_from_slice(b"A\0\0\0");
+		expected.extend_from_slice(&1.5f32.to_be_bytes());
+		expected.extend_from_slice(&1i32.to_be_bytes());
+		expected.extend_from_slice(&2.0f64.to_be_bytes());
+		assert_eq!(osc(&event), expected);
+
+		let event = Event::Reading {
+			scancode: 0,
+			key: None,
+			value: 0.5,
+			ts: 2.0,
+		};
+		let mut expected = b"/wooting/reading\0\0\0\0,isfd\0\0\0".to_vec();
+		expected.extend_from_slice(&0i32.to_be_bytes());
+		expected.extend_from_slice(b"\0\0\0\0");
+		expected.extend_from_slice(&0.5f32.to_be_bytes());
+		expected.extend_from_slice(&2.0f64.to_be_bytes());
+		assert_eq!(osc(&event), expected);
+	}
+
+	#[test]
+	fn encodes_json_messages() {
+		let event = Event::Reading {
+			scancode: 30,
+			key: Some("A".to_string()),
+			value: 0.5,
+			ts: 2.0,
+		};
+		assert_eq!(
+			serde_json::to_string(&event).unwrap(),
+			r#"{"type":"reading","scancode":30,"key":"A","value":0.5,"ts":2.0}"#
+		);
+	}
+
+	#[test]
+	fn sends_presses_to_the_address() {
+		let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+		listener
+			.set_read_timeout(Some(Duration::from_secs(5)))
+			.unwrap();
+		let config = StreamConfig {
+			address: Some(listener.local_addr().unwrap().to_string()),
+			format: StreamFormat::Json,
+		};
+		let (stream, thread) = Stream::spawn(&config);
+		stream.key(&watcher::KeyEvent {
+			device: DeviceID::default(),
+			scancode: 30,
+			caps: false,
+			capitalise: false,
+			velocity: 1.0,
+			ts: Instant::now(),
+		});
+		drop(stream);
+		thread.join().unwrap();
+
+		let mut buf = [0; 1024];
+		let len = listener.recv(&mut buf).unwrap();
+		let message: serde_json::Value = serde_json::from_slice(&buf[..len]).unwrap();
+		assert_eq!(message["type"], "key");
+		assert_eq!(message["key"], "A");
+		assert_eq!(message["caps"], false);
+		let now = unix_time(Instant::now());
+		assert!(
+			(now - message["ts"].as_f64().unwrap()).abs() < 5.0,
+			"{message}"
+		);
+	}
+
+	#[test]
+	fn starts_sending_once_reconfigured() {
+		let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+		listener
+			.set_read_timeout(Some(Duration::from_secs(5)))
+			.unwrap();
+		let (stream, thread) = Stream::spawn(&StreamConfig::default());
+		let reading = |scancode| hid::AnalogueReading {
+			scancode,
+			value: 0.5,
+			ts: Instant::now(),
+		};
+		stream.reading(&reading(30));
+		stream.reconfigure(&StreamConfig {
+			address: Some(listener.local_addr().unwrap().to_string()),
+			format: StreamFormat::Json,
+		});
+		stream.reading(&reading(48));
+		drop(stream);
+		thread.join().unwrap();
+
+		let mut buf = [0; 1024];
+		let len = listener.recv(&mut buf).unwrap();
+		let message: serde_json::Value = serde_json::from_slice(&buf[..len]).unwrap();
+		assert_eq!(message["key"], "B");
+	}
+}
@@ -0,0 +1,95 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::error;
+
+/// How often the watched thread should beat even with nothing to do, and how often it's checked
+pub const TICK: Duration = Duration::from_millis(100);
+/// How long the watched thread can go without beating before it counts as stuck
+const TIMEOUT: Duration = Duration::from_secs(3);
+
+const FINISHED: u64 = u64::MAX;
+
+/// What the panic hook calls to give the keyboard back, see `set_rescue`
+static RESCUE: Mutex<Option<Box<dyn Fn() + Send>>> = Mutex::new(None);
+
+/// Shared between a thread that should keep making progress and the watchdog checking on it.
+#[derive(Clone)]
+pub struct Heartbeat {
+	start: Instant,
+	/// Milliseconds after `start` of the last beat, or `FINISHED`
+	last: Arc<AtomicU64>,
+}
+
+impl Heartbeat {
+	pub fn new() -> Self {
+		Heartbeat {
+			start: Instant::now(),
+			last: Arc::new(AtomicU64::new(0)),
+		}
+	}
+
+	pub fn beat(&self) {
+		let now = self.start.elapsed().as_millis() as u64;
+		let _ = self
+			.last
+			.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| {
+				(last != FINISHED).then_some(now)
+			});
+	}
+
+	/// A guard that tells the watchdog to stop once the watched thread is done, however it ends.
+	pub fn finish_on_drop(&self) -> FinishGuard {
+		FinishGuard(self.clone())
+	}
+}
+
+pub struct FinishGuard(Heartbeat);
+
+impl Drop for FinishGuard {
+	fn drop(&mut self) {
+		self.0.last.store(FINISHED, Ordering::Relaxed);
+	}
+}
+
+/// Watch `heartbeat` until it finishes. If it stops beating for `TIMEOUT`, call `on_stall` to
+/// hand the keyboard back and exit, which also takes down our uinput device and anything it had
+/// pressed.
+pub fn spawn(heartbeat: Heartbeat, on_stall: Box<dyn Fn() + Send>) -> JoinHandle<()> {
+	thread::spawn(move || loop {
+		thread::sleep(TICK);
+		let last = heartbeat.last.load(Ordering::Relaxed);
+		if last == FINISHED {
+			return;
+		}
+		let since = heartbeat.start.elapsed().saturating_sub(Duration::from_millis(last));
+		if since > TIMEOUT {
+			error!("pipeline has been stuck for {since:?}, giving the keyboard back and exiting");
+			on_stall();
+			std::process::exit(1);
+		}
+	})
+}
+
+/// Have the hook from `install_panic_hook` call `rescue` rather than whatever it had before, or
+/// nothing if `None`.
+pub fn set_rescue(rescue: Option<Box<dyn Fn() + Send>>) {
+	*RESCUE.lock().unwrap_or_else(PoisonError::into_inner) = rescue;
+}
+
+/// When any thread panics, give the keyboard back with whatever was given to `set_rescue` and
+/// exit, rather than carry on half working. Exiting also takes down our uinput devices. Install
+/// it once, at startup.
+pub fn install_panic_hook() {
+	let default_hook = std::panic::take_hook();
+	std::panic::set_hook(Box::new(move |info| {
+		default_hook(info);
+		error!("panicked, giving the keyboard back and exiting");
+		if let Some(rescue) = &*RESCUE.lock().unwrap_or_else(PoisonError::into_inner) {
+			rescue();
+		}
+		std::process::exit(1);
+	}));
+}
@@ -1,6 +1,10 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc::SendError;
+use std::time::Duration;
 
-use crate::hid;
+use wooting_analog_plugin_dev::wooting_analog_common::DeviceID;
+
+use crate::{calibration::Calibration, hid};
 
 // struct KeyState {
 //     press_out_started: bool,
@@ -15,51 +19,154 @@ enum KeyState {
 		current_value: f32,
 		current_time: std::time::Instant,
 	},
+	/// Deep enough to fire, but held back until we know whether it is typing or a chord
+	PressPending {
+		event: KeyEvent,
+		/// When it last went past `Chords::depth`, if it is still that deep
+		deep_since: Option<std::time::Instant>,
+	},
 	PressFired,
 }
 
 pub struct KeyWatcher {
 	keys: HashMap<u16, KeyState>,
 	tx: std::sync::mpsc::SyncSender<crate::OutputHidEvent>,
+	/// The keyboard whose keys we are watching
+	device: DeviceID,
+	calibration: Calibration,
+	chords: Chords,
+}
+
+/// Keys that send a chord when held all the way down, resolved from `config::ChordConfig`.
+///
+/// A press of one of `keys` that fires is held back until it is let go, which types it, or has
+/// been past `depth` for `hold`, which sends it with `modifiers` held. Held lightly for `hold`,
+/// it types after all and autorepeats as usual.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Chords {
+	pub keys: HashSet<u16>,
+	pub modifiers: Vec<u16>,
+	pub depth: f32,
+	pub hold: Duration,
+}
+
+/// Fills in the releases the keyboard doesn't report: a key that was down in the last report
+/// but is missing from this one has been released.
+#[derive(Default)]
+pub struct ReleaseTracker {
+	last_pressed: HashSet<u16>,
+}
+
+impl ReleaseTracker {
+	/// Append a zero reading at `ts` for every key that has disappeared since the last report.
+	pub fn complete(
+		&mut self,
+		mut readings: Vec<hid::AnalogueReading>,
+		ts: std::time::Instant,
+	) -> Vec<hid::AnalogueReading> {
+		let pressed: HashSet<u16> = readings
+			.iter()
+			.filter(|r| r.value > 0.0)
+			.map(|r| r.scancode)
+			.collect();
+		let reported: HashSet<u16> = readings.iter().map(|r| r.scancode).collect();
+		for code in self.last_pressed.difference(&reported) {
+			readings.push(hid::AnalogueReading {
+				scancode: *code,
+				value: 0.0,
+				ts,
+			});
+		}
+		self.last_pressed = pressed;
+		readings
+	}
 }
 
+/// The output thread has gone, so there is nowhere to send presses any more.
+#[derive(Debug)]
+pub struct OutputClosed;
+
+impl From<SendError<crate::OutputHidEvent>> for OutputClosed {
+	fn from(_: SendError<crate::OutputHidEvent>) -> Self {
+		OutputClosed
+	}
+}
+
+#[derive(Clone)]
 pub struct KeyEvent {
+	/// The keyboard it was pressed on
+	pub device: DeviceID,
 	pub scancode: u16,
 	pub caps: bool,
+	/// Comes out upper case whether or not it shouts, as it starts a sentence. Unlike `caps`,
+	/// it doesn't make the press hard enough for a tier
+	pub capitalise: bool,
 	pub velocity: f32,
+	/// When the reading that fired this press was taken
+	pub ts: std::time::Instant,
 }
 
-const THRESHOLD_LOW: f32 = 0.4;
-const THRESHOLD: f32 = 0.92;
+/// A key held down deep enough to send with `modifiers`, see `Chords`.
+pub struct ChordEvent {
+	pub device: DeviceID,
+	pub scancode: u16,
+	pub modifiers: Vec<u16>,
+	/// When the reading that fired this chord was taken
+	pub ts: std::time::Instant,
+}
+
+const OFFLINE_CHANNEL_BUF_SIZE: usize = 64;
+
+pub const THRESHOLD_LOW: f32 = 0.4;
+pub const THRESHOLD: f32 = 0.92;
+pub const VELOCITY_CUTOFF: f32 = 180.0;
 
 impl KeyWatcher {
-	pub fn new(tx: std::sync::mpsc::SyncSender<crate::OutputHidEvent>) -> Self {
+	pub fn new(
+		tx: std::sync::mpsc::SyncSender<crate::OutputHidEvent>,
+		device: DeviceID,
+		calibration: Calibration,
+		chords: Chords,
+	) -> Self {
 		return Self {
 			keys: HashMap::<_, _>::with_capacity(255),
 			tx: tx,
+			device,
+			calibration,
+			chords,
 		};
 	}
-	fn get_key_state(&mut self, code: u16) -> &mut KeyState {
-		if !self.keys.contains_key(&code) {
-			self.keys.insert(code, KeyState::Released);
-		}
-		return self.keys.get_mut(&code).unwrap();
+	pub fn reconfigure(&mut self, calibration: Calibration, chords: Chords) {
+		self.calibration = calibration;
+		self.chords = chords;
 	}
 
-	pub fn take_input(&mut self, input: &hid::AnalogueReading) {
+	/// Follow a key to `input`, sending what it does to the output thread. Fails once that has
+	/// gone.
+	pub fn take_input(&mut self, input: &hid::AnalogueReading) -> Result<(), OutputClosed> {
 		let hid::AnalogueReading {
 			scancode: code,
 			value,
 			ts,
 		} = input;
 		let tx = &self.tx.clone();
-		let s = self.get_key_state(*code);
+		let cfg = self.calibration.for_key(*code);
+		let chords = &self.chords;
+		let device = self.device;
+		let s = self.keys.entry(*code).or_insert(KeyState::Released);
+		// whether this key fired, so keys pending from before go out ahead of it
+		let mut fired = false;
+		let mut typed = None;
 
 		//let code = key_id.to_u16().expect("Failed to convert HIDCode to u16");
 		//let value = analog_data.get(&code).unwrap_or(&0.0);
 
 		//info!("val for {code} is {value}");
 		match (&s, *value > 0.0) {
+			(KeyState::PressStarted { .. }, false) => {
+				// let go before it got deep enough to fire
+				*s = KeyState::Released;
+			}
 			(
 				KeyState::PressStarted {
 					start_time,
@@ -70,8 +177,8 @@ impl KeyWatcher {
 			) => {
 				// started release
 				let diff = *value - *current_value;
-				if *value > THRESHOLD // key nearly fully depressed
-				|| diff < 0.0 && *value > THRESHOLD_LOW
+				if *value > cfg.threshold // key nearly fully depressed
+				|| diff < 0.0 && *value > cfg.threshold_low
 				// started release
 				// key has begun to be depressed
 				{
@@ -83,13 +190,24 @@ impl KeyWatcher {
 
 					let velocity = (*value - 0.0) / tdiff.as_secs_f32();
 
-					tx.send(crate::OutputHidEvent::Key(KeyEvent {
+					let event = KeyEvent {
+						device,
 						scancode: *code,
-						caps: (velocity > 180.0),
+						caps: (velocity > cfg.velocity_cutoff),
+						capitalise: false,
 						velocity,
-					}))
-					.unwrap();
-					*s = KeyState::PressFired
+						ts: *ts,
+					};
+					if chords.keys.contains(code) {
+						*s = KeyState::PressPending {
+							event,
+							deep_since: None,
+						};
+					} else {
+						typed = Some(event);
+						*s = KeyState::PressFired
+					}
+					fired = true;
 				} else {
 					*s = KeyState::PressStarted {
 						start_time: *start_time,
@@ -98,10 +216,42 @@ impl KeyWatcher {
 					};
 				}
 			}
+			(KeyState::PressPending { event, .. }, false) => {
+				// a tap, type it now that we know
+				tx.send(crate::OutputHidEvent::Key(event.clone()))?;
+				tx.send(crate::OutputHidEvent::KeyRelease(device, *code, *ts))?;
+				*s = KeyState::Released;
+			}
+			(KeyState::PressPending { event, deep_since }, true) => {
+				let deep_since = if *value >= chords.depth {
+					Some(deep_since.unwrap_or(*ts))
+				} else {
+					None
+				};
+				if deep_since.is_some_and(|since| *ts - since >= chords.hold) {
+					tx.send(crate::OutputHidEvent::Chord(ChordEvent {
+						device,
+						scancode: *code,
+						modifiers: chords.modifiers.clone(),
+						ts: *ts,
+					}))?;
+					*s = KeyState::PressFired;
+				} else if deep_since.is_none() && *ts - event.ts >= chords.hold {
+					// held lightly, so it is typing and should autorepeat
+					tx.send(crate::OutputHidEvent::Key(event.clone()))?;
+					*s = KeyState::PressFired;
+				} else {
+					*s = KeyState::PressPending {
+						event: event.clone(),
+						deep_since,
+					};
+				}
+			}
 			(KeyState::PressFired, true) => {
 				//*s = *s;
 			}
 			(KeyState::PressFired, false) => {
+				tx.send(crate::OutputHidEvent::KeyRelease(device, *code, *ts))?;
 				*s = KeyState::Released;
 			}
 			(KeyState::Released, true) => {
@@ -115,5 +265,204 @@ impl KeyWatcher {
 				*s = KeyState::Released;
 			}
 		}
+
+		if fired {
+			// another key going down means whatever is pending was typing
+			for event in self.take_pending(Some(*code)).into_iter().chain(typed) {
+				tx.send(crate::OutputHidEvent::Key(event))?;
+			}
+		}
+		Ok(())
+	}
+
+	/// Type every press held back in case it was a chord, as a key that doesn't go through the
+	/// watcher is about to be typed after it.
+	pub fn flush_pending(&mut self) -> Result<(), OutputClosed> {
+		for event in self.take_pending(None) {
+			self.tx.send(crate::OutputHidEvent::Key(event))?;
+		}
+		Ok(())
+	}
+
+	/// The presses held back in case they were chords, other than `except`, in the order they
+	/// came. They count as fired from now on.
+	fn take_pending(&mut self, except: Option<u16>) -> Vec<KeyEvent> {
+		let mut pending: Vec<KeyEvent> = vec![];
+		for (code, state) in self.keys.iter_mut() {
+			if let KeyState::PressPending { event, .. } = state {
+				if Some(*code) != except {
+					pending.push(event.clone());
+					*state = KeyState::PressFired;
+				}
+			}
+		}
+		pending.sort_by_key(|event| event.ts);
+		pending
+	}
+}
+
+/// Run recorded `reports` through a `KeyWatcher`, handing each press it fires to `on_press`.
+///
+/// With `realtime`, wait between reports so they come in with their original timing.
+pub fn run_offline(
+	calibration: &Calibration,
+	reports: Vec<(std::time::Instant, Vec<hid::AnalogueReading>)>,
+	realtime: bool,
+	mut on_press: impl FnMut(KeyEvent),
+) {
+	let (tx, rx) = std::sync::mpsc::sync_channel(OFFLINE_CHANNEL_BUF_SIZE);
+	// recordings don't say which keyboard they came from
+	let mut watcher = KeyWatcher::new(
+		tx,
+		DeviceID::default(),
+		calibration.clone(),
+		Chords::default(),
+	);
+	let mut releases = ReleaseTracker::default();
+
+	for (ts, readings) in reports {
+		if realtime {
+			std::thread::sleep(ts.saturating_duration_since(std::time::Instant::now()));
+		}
+		for reading in &releases.complete(readings, ts) {
+			// we hold the receiving end, so this can't fail
+			let _ = watcher.take_input(reading);
+		}
+		for ev in rx.try_iter() {
+			if let crate::OutputHidEvent::Key(k) = ev {
+				on_press(k);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::mpsc::Receiver;
+	use std::time::Instant;
+
+	use super::*;
+
+	const J: u16 = 36;
+	const K: u16 = 37;
+	const CTRL: u16 = 29;
+
+	/// A watcher with `J` as a chord key, fed readings by milliseconds since it started.
+	struct Harness {
+		watcher: KeyWatcher,
+		rx: Receiver<crate::OutputHidEvent>,
+		start: Instant,
+	}
+
+	impl Harness {
+		fn new() -> Self {
+			let (tx, rx) = std::sync::mpsc::sync_channel(OFFLINE_CHANNEL_BUF_SIZE);
+			let chords = Chords {
+				keys: HashSet::from([J]),
+				modifiers: vec![CTRL],
+				depth: 0.95,
+				hold: Duration::from_millis(200),
+			};
+			Harness {
+				watcher: KeyWatcher::new(tx, DeviceID::default(), Calibration::default(), chords),
+				rx,
+				start: Instant::now(),
+			}
+		}
+
+		fn at(&mut self, ms: u64, scancode: u16, value: f32) -> &mut Self {
+			let ts = self.start + Duration::from_millis(ms);
+			self.watcher
+				.take_input(&hid::AnalogueReading {
+					scancode,
+					value,
+					ts,
+				})
+				.unwrap();
+			self
+		}
+
+		fn sent(&self) -> Vec<String> {
+			self.rx
+				.try_iter()
+				.map(|ev| match ev {
+					crate::OutputHidEvent::Key(k) => format!("key {}", k.scancode),
+					crate::OutputHidEvent::KeyRelease(_, code, _) => format!("release {code}"),
+					crate::OutputHidEvent::Chord(c) => {
+						format!("chord {} {:?}", c.scancode, c.modifiers)
+					}
+					_ => "other".to_string(),
+				})
+				.collect()
+		}
+	}
+
+	#[test]
+	fn taps_type_once_let_go() {
+		let mut h = Harness::new();
+		h.at(0, J, 0.5).at(10, J, 1.0).at(20, J, 1.0);
+		assert_eq!(h.sent(), Vec::<String>::new());
+		h.at(30, J, 0.0);
+		assert_eq!(h.sent(), ["key 36", "release 36"]);
+	}
+
+	#[test]
+	fn holding_deep_sends_a_chord() {
+		let mut h = Harness::new();
+		h.at(0, J, 0.5)
+			.at(10, J, 1.0)
+			.at(20, J, 1.0)
+			.at(210, J, 1.0);
+		assert_eq!(h.sent(), Vec::<String>::new());
+		h.at(220, J, 1.0);
+		assert_eq!(h.sent(), ["chord 36 [29]"]);
+		h.at(230, J, 1.0).at(240, J, 0.0);
+		assert_eq!(h.sent(), ["release 36"]);
+	}
+
+	#[test]
+	fn holding_lightly_types() {
+		let mut h = Harness::new();
+		h.at(0, J, 0.5).at(10, J, 0.93).at(100, J, 0.93);
+		assert_eq!(h.sent(), Vec::<String>::new());
+		h.at(210, J, 0.93);
+		assert_eq!(h.sent(), ["key 36"]);
+		h.at(300, J, 0.0);
+		assert_eq!(h.sent(), ["release 36"]);
+	}
+
+	#[test]
+	fn easing_off_restarts_the_hold() {
+		let mut h = Harness::new();
+		h.at(0, J, 0.5)
+			.at(10, J, 1.0)
+			.at(20, J, 1.0)
+			.at(150, J, 0.93);
+		h.at(160, J, 1.0).at(250, J, 1.0);
+		assert_eq!(h.sent(), Vec::<String>::new());
+		h.at(360, J, 1.0);
+		assert_eq!(h.sent(), ["chord 36 [29]"]);
+	}
+
+	#[test]
+	fn another_key_firing_types_the_pending_one_first() {
+		let mut h = Harness::new();
+		h.at(0, J, 0.5).at(10, J, 1.0).at(20, K, 0.5);
+		assert_eq!(h.sent(), Vec::<String>::new());
+		h.at(30, K, 1.0);
+		assert_eq!(h.sent(), ["key 36", "key 37"]);
+		h.at(40, J, 0.0).at(50, K, 0.0);
+		assert_eq!(h.sent(), ["release 36", "release 37"]);
+	}
+
+	#[test]
+	fn flushing_types_pending_presses() {
+		let mut h = Harness::new();
+		h.at(0, J, 0.5).at(10, J, 1.0);
+		h.watcher.flush_pending().unwrap();
+		assert_eq!(h.sent(), ["key 36"]);
+		h.watcher.flush_pending().unwrap();
+		h.at(20, J, 0.0);
+		assert_eq!(h.sent(), ["release 36"]);
 	}
 }
@@ -0,0 +1,379 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use wooting_analog_plugin_dev::wooting_analog_common::DeviceID;
+
+use crate::config::{OutputConfig, ShoutMode, WordMode};
+use crate::watcher::{ChordEvent, KeyEvent};
+use crate::{outputhid, sink::OutputSink};
+
+/// A letter of the word being typed.
+struct Letter {
+	press: KeyEvent,
+	/// Whether Shift and Caps Lock alone would have made it upper case when it was pressed
+	natural: bool,
+}
+
+/// Something held back in `WordMode::Delay` until the word ends.
+enum Held {
+	Press(KeyEvent),
+	Release(DeviceID, u16, Instant),
+	Passthrough(Vec<input_linux::sys::input_event>, Instant),
+}
+
+/// Decides whether to shout a word at a time rather than a letter at a time, in front of the
+/// sink that does the typing.
+///
+/// A word is a run of letters on `config.layout`. It ends with any other key, watched or passed
+/// through, a chord, or after `config.words.timeout_ms` without another letter. Shift and
+/// releases don't end it. If at least `config.words.threshold` of its letters were hit hard the
+/// whole word shouts, and otherwise none of it does, so a stray hard press doesn't come out as
+/// `HeLLo`. Whatever the mode, the `append` of `config.tiers` waits for the end of the word, so it
+/// comes after it rather than in the middle.
+///
+/// In `WordMode::Retype` a word also ends once one of its letters has been held down for
+/// `outputhid::REPEAT_DELAY`, as there is no knowing how many times it repeated to backspace over.
+pub struct WordSink {
+	inner: Box<dyn OutputSink>,
+	config: OutputConfig,
+	modifiers: outputhid::Modifiers,
+	word: Vec<Letter>,
+	/// When the word ends if nothing else ends it first
+	deadline: Option<Instant>,
+	/// Everything since the word started, in `WordMode::Delay`
+	held_back: Vec<Held>,
+	/// Letters of the word given to `inner` and not yet released, with when they were pressed, in
+	/// `WordMode::Retype`
+	pressed: HashMap<(DeviceID, u16), Instant>,
+	/// Letters we released early to retype them, whose real release is dropped
+	released_early: HashSet<(DeviceID, u16)>,
+	/// Text from `config.tiers` to type once the word ends
+	append: String,
+}
+
+impl WordSink {
+	pub fn new(inner: Box<dyn OutputSink>, config: OutputConfig) -> Self {
+		WordSink {
+			inner,
+			config,
+			modifiers: outputhid::Modifiers::default(),
+			word: vec![],
+			deadline: None,
+			held_back: vec![],
+			pressed: HashMap::new(),
+			released_early: HashSet::new(),
+			append: String::new(),
+		}
+	}
+
+	/// Decide how the word shouts and make it come out that way, then type the `append` of any
+	/// tiers its letters reached, as if read at `ts` along with whatever ended it.
+	fn end_word(&mut self, ts: Instant) {
+		self.deadline = None;
+		let word = std::mem::take(&mut self.word);
+		if !word.is_empty() {
+			self.shout_word(word, ts);
+		}
+		let append = std::mem::take(&mut self.append);
+		if !append.is_empty() {
+			self.inner.send_text(&append, ts);
+		}
+	}
+
+	fn shout_word(&mut self, word: Vec<Letter>, ts: Instant) {
+		let hard = word.iter().filter(|l| l.press.caps).count();
+		let shout = hard as f32 / word.len() as f32 >= self.config.words.threshold;
+
+		match self.config.words.mode {
+			WordMode::Off => word.iter().for_each(|l| self.queue_append(&l.press)),
+			WordMode::Delay => {
+				log::debug!("{hard} of {} letters hit hard, shouting the word: {shout}", word.len());
+				for held in std::mem::take(&mut self.held_back) {
+					match held {
+						Held::Press(k) => {
+							let k = KeyEvent { caps: shout, ..k };
+							self.inner.send_key(&k);
+							self.queue_append(&k);
+						}
+						Held::Release(device, code, ts) => self.inner.send_key_release(device, code, ts),
+						Held::Passthrough(evs, ts) => self.inner.send_passthrough(&evs, ts),
+					}
+				}
+			}
+			WordMode::Retype => {
+				log::debug!("{hard} of {} letters hit hard, shouting the word: {shout}", word.len());
+				let pressed = std::mem::take(&mut self.pressed);
+				if Self::repeating(&pressed, Instant::now()) {
+					// we can't tell how many characters it typed to take back
+					log::debug!("not retyping a word with a letter held long enough to repeat");
+					word.iter().for_each(|l| self.queue_append(&l.press));
+					return;
+				}
+				let first = word
+					.iter()
+					.position(|l| l.press.caps != shout)
+					.unwrap_or(word.len());
+				word[..first].iter().for_each(|l| self.queue_append(&l.press));
+				let retyped = &word[first..];
+				if retyped.is_empty() {
+					return;
+				}
+				for (device, code) in pressed.into_keys() {
+					self.inner.send_key_release(device, code, ts);
+					self.released_early.insert((device, code));
+				}
+
+				let backspace = input_linux::Key::Backspace;
+				for _ in retyped {
+					self.inner.send_passthrough(&tap(backspace), ts);
+				}
+
+				let layout = self.config.layout;
+				for letter in retyped {
+					let code = letter.press.scancode;
+					let want = self.config.shout_mode.upper(shout, letter.natural);
+					let caps = match self.config.shout_mode {
+						ShoutMode::ForceShift => want,
+						ShoutMode::InvertCase => want ^ self.modifiers.upper(layout, code),
+					};
+					let k = KeyEvent {
+						caps,
+						..letter.press.clone()
+					};
+					self.inner.send_key(&k);
+					self.inner.send_key_release(letter.press.device, code, ts);
+					self.queue_append(&k);
+				}
+			}
+		}
+	}
+
+	/// Whether any of `pressed` has been held long enough for `inner` to autorepeat it by `now`.
+	fn repeating(pressed: &HashMap<(DeviceID, u16), Instant>, now: Instant) -> bool {
+		pressed.values().any(|ts| *ts + outputhid::REPEAT_DELAY <= now)
+	}
+
+	/// When the first letter still held starts to autorepeat, which ends the word without
+	/// retyping it.
+	fn repeat_due(&self) -> Option<Instant> {
+		self.pressed.values().min().map(|ts| *ts + outputhid::REPEAT_DELAY)
+	}
+
+	/// Add the `append` of the tier `k` reached, if any, to what is typed when the word ends.
+	fn queue_append(&mut self, k: &KeyEvent) {
+		if let Some(tier) = self.config.tier(k) {
+			self.append.push_str(&tier.append);
+		}
+	}
+}
+
+/// Whether `evs` press a key that ends a word, i.e. anything but Shift or Caps Lock.
+fn ends_word(evs: &[input_linux::sys::input_event]) -> bool {
+	use input_linux::Key;
+	evs.iter().any(|e| {
+		e.type_ == input_linux::sys::EV_KEY as u16
+			&& e.value == 1
+			&& ![Key::LeftShift, Key::RightShift, Key::CapsLock]
+				.iter()
+				.any(|k| u16::from(*k) == e.code)
+	})
+}
+
+/// A press and release of `key`, as read from evdev.
+fn tap(key: input_linux::Key) -> Vec<input_linux::sys::input_event> {
+	let time = libc::timeval {
+		tv_sec: 0,
+		tv_usec: 0,
+	};
+	let event = |type_: u32, code: u16, value: i32| input_linux::sys::input_event {
+		time,
+		type_: type_ as u16,
+		code,
+		value,
+	};
+	let syn = input_linux::sys::SYN_REPORT as u16;
+	vec![
+		event(input_linux::sys::EV_KEY as u32, u16::from(key), 1),
+		event(input_linux::sys::EV_SYN as u32, syn, 0),
+		event(input_linux::sys::EV_KEY as u32, u16::from(key), 0),
+		event(input_linux::sys::EV_SYN as u32, syn, 0),
+	]
+}
+
+impl OutputSink for WordSink {
+	fn send_key(&mut self, k: &KeyEvent) {
+		let mode = self.config.words.mode;
+		if !self.config.layout.is_letter(k.scancode) {
+			self.end_word(k.ts);
+			self.inner.send_key(k);
+			self.queue_append(k);
+			return self.end_word(k.ts);
+		}
+
+		self.word.push(Letter {
+			press: k.clone(),
+			natural: self.modifiers.upper(self.config.layout, k.scancode),
+		});
+		self.deadline = Some(Instant::now() + Duration::from_millis(self.config.words.timeout_ms));
+		if mode == WordMode::Off {
+			self.inner.send_key(k);
+		} else if mode == WordMode::Delay {
+			self.held_back.push(Held::Press(k.clone()));
+		} else {
+			self.pressed.insert((k.device, k.scancode), k.ts);
+			self.inner.send_key(k);
+		}
+	}
+
+	fn send_key_release(&mut self, device: DeviceID, code: u16, ts: Instant) {
+		if self.released_early.remove(&(device, code)) {
+			return;
+		}
+		let repeating = self.repeat_due().is_some_and(|due| due <= Instant::now());
+		if repeating && self.pressed.contains_key(&(device, code)) {
+			self.end_word(ts);
+		}
+		self.pressed.remove(&(device, code));
+		let held_back = self
+			.held_back
+			.iter()
+			.any(|held| matches!(held, Held::Press(k) if (k.device, k.scancode) == (device, code)));
+		if held_back {
+			self.held_back.push(Held::Release(device, code, ts));
+		} else {
+			self.inner.send_key_release(device, code, ts);
+		}
+	}
+
+	fn send_chord(&mut self, c: &ChordEvent) {
+		self.end_word(c.ts);
+		self.inner.send_chord(c);
+	}
+
+	fn send_passthrough(&mut self, evs: &[input_linux::sys::input_event], ts: Instant) {
+		if ends_word(evs) {
+			self.end_word(ts);
+		}
+		for e in evs {
+			self.modifiers.observe(e);
+		}
+		if self.held_back.is_empty() {
+			self.inner.send_passthrough(evs, ts);
+		} else {
+			self.held_back.push(Held::Passthrough(evs.to_vec(), ts));
+		}
+	}
+
+	fn send_text(&mut self, text: &str, ts: Instant) {
+		self.end_word(ts);
+		self.inner.send_text(text, ts);
+	}
+
+	fn reconfigure(&mut self, config: OutputConfig) {
+		self.end_word(Instant::now());
+		self.config = config.clone();
+		self.inner.reconfigure(config);
+	}
+
+	fn releaser(&self) -> Option<Box<dyn Fn() + Send>> {
+		self.inner.releaser()
+	}
+
+	fn next_due(&self) -> Option<Instant> {
+		[self.inner.next_due(), self.deadline, self.repeat_due()]
+			.into_iter()
+			.flatten()
+			.min()
+	}
+
+	fn flush(&mut self) {
+		let now = Instant::now();
+		let due = [self.deadline, self.repeat_due()].into_iter().flatten().min();
+		if due.is_some_and(|due| due <= now) {
+			self.end_word(now);
+		}
+		self.inner.flush();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use input_linux::Key;
+
+	use crate::sink::{testing::Buffer, TextSink};
+
+	use super::*;
+
+	/// A word sink for `toml` in `[output]`, typing into `Buffer`.
+	fn word_sink(toml: &str) -> (WordSink, Buffer) {
+		let config: OutputConfig = toml::from_str(toml).unwrap();
+		let out = Buffer::default();
+		let text = TextSink::new(Box::new(out.clone()), config.clone());
+		(WordSink::new(Box::new(text), config), out)
+	}
+
+	/// Press and release `key`, hard enough to shout if `hard`.
+	fn type_key(sink: &mut WordSink, key: Key, hard: bool) {
+		let k = KeyEvent {
+			device: DeviceID::default(),
+			scancode: u16::from(key),
+			caps: hard,
+			capitalise: false,
+			velocity: if hard { 400.0 } else { 50.0 },
+			ts: Instant::now(),
+		};
+		sink.send_key(&k);
+		sink.send_key_release(k.device, k.scancode, k.ts);
+	}
+
+	fn space(sink: &mut WordSink) {
+		sink.send_passthrough(&tap(Key::Space), Instant::now());
+	}
+
+	#[test]
+	fn delay_holds_words_back_to_type_in_one_case() {
+		let (mut sink, out) = word_sink("[words]\nmode = \"delay\"\n");
+		type_key(&mut sink, Key::A, false);
+		type_key(&mut sink, Key::B, true);
+		type_key(&mut sink, Key::C, true);
+		assert_eq!(out.text(), "");
+		space(&mut sink);
+		assert_eq!(out.text(), "ABC ");
+
+		type_key(&mut sink, Key::A, true);
+		type_key(&mut sink, Key::B, false);
+		type_key(&mut sink, Key::C, false);
+		space(&mut sink);
+		assert_eq!(out.text(), "ABC abc ");
+	}
+
+	#[test]
+	fn delay_ends_words_after_the_timeout() {
+		let (mut sink, out) = word_sink("[words]\nmode = \"delay\"\ntimeout_ms = 0\n");
+		type_key(&mut sink, Key::H, true);
+		type_key(&mut sink, Key::I, true);
+		assert_eq!(out.text(), "");
+		assert!(sink.next_due().is_some_and(|due| due <= Instant::now()));
+		sink.flush();
+		assert_eq!(out.text(), "HI");
+		assert_eq!(sink.next_due(), None);
+	}
+
+	#[test]
+	fn appends_tiers_once_the_word_ends() {
+		let tier = "[[tiers]]\nvelocity = 300.0\nappend = \"!\"\n";
+		let (mut sink, out) = word_sink(tier);
+		type_key(&mut sink, Key::H, true);
+		type_key(&mut sink, Key::I, false);
+		assert_eq!(out.text(), "Hi");
+		space(&mut sink);
+		assert_eq!(out.text(), "Hi! ");
+
+		let (mut sink, out) = word_sink(&format!("{tier}[words]\nmode = \"delay\"\n"));
+		// the I only shouts along with the word, and isn't fast enough for the tier
+		type_key(&mut sink, Key::H, true);
+		type_key(&mut sink, Key::I, false);
+		type_key(&mut sink, Key::Dot, false);
+		assert_eq!(out.text(), "HI!.");
+	}
+}